@@ -17,9 +17,40 @@ pub struct Args {
     ///
     /// Expressions should generally be of the form 2d6. Shorthand like 20 is valid for any
     /// expression 1d20, etc. Syntax extensions include:
+    /// - a20 / s20: roll with advantage or disadvantage (keep the higher or lower of two rolls)
+    /// - aad20 / ssd20: each extra letter rolls once more, e.g. the best of three d20s
+    /// - a(2d6+3): roll the whole expression twice and keep the higher total
+    /// - 4dF / d%: Fate/Fudge dice (-1, 0, +1) and percentile dice
+    /// - d{1,1,2,2,3,4} / 3d{combat}: dice with custom faces, or a die stored with `roll die`;
+    ///   a lone name in braces is always a stored die, so a one-face die is written d{hit=0}
     /// - 2d6r / 2d6r2: reroll 1s or 2s
+    /// - 2d6r<3 / 2d6r=1 / 2d6ro<3: reroll by comparison, or reroll only once (ro)
     /// - 2d6!: explode (roll again and add to total) on max values
+    /// - 2d10!>=9: explode by comparison
+    /// - 2d6!! / 2d6!p: compounding explosions fold into one die; penetrating ones count 1 less
+    /// - 4d6kh3 / 2d20kl1: keep the highest three or lowest one (also dh, dl to drop)
+    /// - 10d10>=8 / 6d6>4f1: count successes (and subtract failures) instead of summing
+    /// - 7wod / 7wod8 / 7wodr / 0wod: World of Darkness pools with 10-, 9- or 8-again, rote or
+    ///   a chance die
+    /// - 12sr / 12sr!: Shadowrun hits on 5 or 6 with glitches, and edge to roll 6s again
+    /// - sw8 / sw8+1 vs 6: Savage Worlds trait die and d6 wild die, with raises per 4 over the TN
+    /// - bitd3 / bitd0: Blades in the Dark action rolls, the highest of three d6s or lowest of two
+    /// - d20c19 / d20cf2: count 19-20 as a critical, or 1-2 as a fumble
+    /// - d20min10 / 2d6max5: count each die below 10 as 10, or above 5 as 5
+    /// - (1d6-3)min0: keep a total within bounds
     /// - 2d6+2: add 2 to total
+    /// - d20+@str: add a variable stored with `roll set str 4`
+    /// - (2d6+3)*2 / 1d8/2 / 1d8/^2: multiply, or divide rounding down or up
+    /// - 2d6+1d4+2: roll several groups of dice and add them together
+    /// - 1d20-1d4 / -- -2d6: subtract dice (put -- before an expression starting with -)
+    /// - 6#4d6kh3 / 3#attack: roll an expression or alias six or three times, numbering each
+    /// - {4d6,4d6,4d6}kh1 / {2d6+1d8}kh2: keep the best totals, or the best dice of one pool
+    /// - max(1d6,1d8) / min(2d6,7) / abs(1d6-1d6): functions of whole expressions
+    /// - floor(3d6/2) / ceil(3d6/2): round a division down or up
+    /// - 2d6[fire]+1d8[slashing]: label terms to show a subtotal for each label
+    /// - d20+5 vs 15: report whether the total reaches 15, and by how much
+    /// - 2d6+@cool bands / 2d6 bands[miss,7:weak hit,10:strong hit]: name the band the total
+    ///   lands in, by default the 6-, 7-9 and 10+ of a Powered by the Apocalypse move
     expressions: Vec<String>,
 
     /// print average value of expressions
@@ -40,6 +71,18 @@ pub struct Args {
 
     #[clap(short, long)]
     pub verbose: bool,
+
+    /// check each total against this target (unless the expression has its own vs N)
+    #[clap(long)]
+    pub dc: Option<i32>,
+
+    /// halve subtotals with this label, rounding down (e.g. --resist fire)
+    #[clap(long, value_name = "LABEL")]
+    pub resist: Vec<String>,
+
+    /// double subtotals with this label (e.g. --vuln cold)
+    #[clap(long = "vuln", value_name = "LABEL")]
+    pub vulnerable: Vec<String>,
 }
 
 impl Args {
@@ -54,7 +97,11 @@ impl Args {
                 ref candidate_expressions,
                 ..
             })) => Either::Left(candidate_expressions.iter().map(AsRef::as_ref)),
-            Some(SubCommand::RemAlias(_)) | Some(SubCommand::List) => Either::Right(iter::empty()),
+            Some(SubCommand::RemAlias(_))
+            | Some(SubCommand::AddDie(_))
+            | Some(SubCommand::SetVariable(_))
+            | Some(SubCommand::Variables)
+            | Some(SubCommand::List) => Either::Right(iter::empty()),
         }
     }
 
@@ -69,6 +116,9 @@ impl Args {
             }
             Some(SubCommand::AddAlias(ref add)) => Mode::Add(add),
             Some(SubCommand::RemAlias(ref rem)) => Mode::Rem(&rem.alias),
+            Some(SubCommand::AddDie(ref die)) => Mode::Die(die),
+            Some(SubCommand::SetVariable(ref set)) => Mode::Set(set),
+            Some(SubCommand::Variables) => Mode::Variables,
             Some(SubCommand::List) => Mode::List,
         }
     }
@@ -106,6 +156,13 @@ enum SubCommand {
     AddAlias(AddAlias),
     #[clap(name = "rm")]
     RemAlias(RemAlias),
+    #[clap(name = "die")]
+    AddDie(AddDie),
+    #[clap(name = "set")]
+    SetVariable(SetVariable),
+    /// List the variables stored in the profile.
+    #[clap(name = "vars")]
+    Variables,
     #[clap(name = "list")]
     List,
 }
@@ -129,12 +186,35 @@ struct RemAlias {
     alias: String,
 }
 
+/// Store a named die for use in expressions, e.g. 2d{combat}.
+#[derive(Clone, Debug, Parser)]
+pub struct AddDie {
+    /// The name used to refer to the die
+    pub name: String,
+    /// Faces of the die: numbers, names (worth zero) or names with values, like crit=2
+    #[clap(required = true)]
+    pub faces: Vec<String>,
+}
+
+/// Store a named value for use in expressions, e.g. d20+@str.
+#[derive(Clone, Debug, Parser)]
+pub struct SetVariable {
+    /// The name used to refer to the value, without the @
+    pub name: String,
+    /// The value, e.g. an ability modifier
+    #[clap(allow_negative_numbers = true)]
+    pub value: i32,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum Mode<'a> {
     Norm,
     Average,
     Add(&'a AddAlias),
     Rem(&'a str),
+    Die(&'a AddDie),
+    Set(&'a SetVariable),
+    Variables,
     List,
 }
 
@@ -0,0 +1,360 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use hashbrown::HashMap;
+
+/// Probability mass below which the tail of an unbounded (exploding) distribution is discarded.
+const EPSILON: f64 = 1e-15;
+
+/// Upper bound on the number of explosions followed when building a distribution.
+const MAX_DEPTH: usize = 200;
+
+/// A discrete probability distribution over integer outcomes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Distribution {
+    outcomes: BTreeMap<i64, f64>,
+}
+
+impl Distribution {
+    pub fn constant(value: i64) -> Self {
+        Self::from_weights([(value, 1.0)])
+    }
+
+    pub fn from_weights(weights: impl IntoIterator<Item = (i64, f64)>) -> Self {
+        let mut outcomes = BTreeMap::new();
+        for (value, p) in weights {
+            if p > 0.0 {
+                *outcomes.entry(value).or_default() += p;
+            }
+        }
+        Self { outcomes }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i64, f64)> + '_ {
+        self.outcomes.iter().map(|(&value, &p)| (value, p))
+    }
+
+    /// Total probability mass, which is less than one for truncated distributions.
+    pub fn mass(&self) -> f64 {
+        self.outcomes.values().sum()
+    }
+
+    pub fn mean(&self) -> f64 {
+        let mean: f64 = self.iter().map(|(value, p)| value as f64 * p).sum();
+        mean / self.mass()
+    }
+
+    /// Probability that the outcome is at least `value`.
+    pub fn at_least(&self, value: i64) -> f64 {
+        // Summing nothing gives -0.0, which would print as -0.00%.
+        self.outcomes
+            .range(value..)
+            .fold(0.0, |sum, (_, &p)| sum + p)
+    }
+
+    /// Distribution of the sum of two independent outcomes.
+    ///
+    /// Sums are tallied in a dense table indexed from the lowest sum, unless the outcomes are so
+    /// spread out that most of the table would be empty.
+    pub fn convolve(&self, other: &Distribution) -> Distribution {
+        let (Some((&a_low, _)), Some((&a_high, _))) = (
+            self.outcomes.first_key_value(),
+            self.outcomes.last_key_value(),
+        ) else {
+            return Distribution::default();
+        };
+        let (Some((&b_low, _)), Some((&b_high, _))) = (
+            other.outcomes.first_key_value(),
+            other.outcomes.last_key_value(),
+        ) else {
+            return Distribution::default();
+        };
+
+        let span = (a_high as i128 - a_low as i128) + (b_high as i128 - b_low as i128) + 1;
+        let dense = 4 * (self.outcomes.len() + other.outcomes.len()) as i128;
+        if span > dense || a_low.checked_add(b_low).is_none() {
+            return self.combine(other, i64::saturating_add);
+        }
+
+        let mut sums = vec![0.0; span as usize];
+        for (a, p) in self.iter() {
+            for (b, q) in other.iter() {
+                sums[(a - a_low + b - b_low) as usize] += p * q;
+            }
+        }
+        let low = a_low + b_low;
+        Self::from_weights(
+            sums.into_iter()
+                .enumerate()
+                .map(|(i, p)| (low + i as i64, p)),
+        )
+    }
+
+    /// Distribution of `f(a, b)` for independent outcomes `a` and `b`.
+    pub fn combine(&self, other: &Distribution, f: impl Fn(i64, i64) -> i64) -> Distribution {
+        Self::from_weights(
+            self.iter()
+                .flat_map(|(a, p)| other.iter().map(move |(b, q)| (a, b, p * q)))
+                .map(|(a, b, p)| (f(a, b), p)),
+        )
+    }
+
+    pub fn map(&self, f: impl Fn(i64) -> i64) -> Distribution {
+        Self::from_weights(self.iter().map(|(value, p)| (f(value), p)))
+    }
+
+    /// Adds the outcomes of `other`, weighted by `factor`, to these.
+    pub fn add_scaled(&mut self, other: &Distribution, factor: f64) {
+        for (value, p) in other.iter() {
+            if p * factor > 0.0 {
+                *self.outcomes.entry(value).or_default() += p * factor;
+            }
+        }
+    }
+
+    /// Distribution of the highest of `n` independent outcomes.
+    ///
+    /// The highest is at most v exactly when every outcome is, so P(max ≤ v) = P(X ≤ v)ⁿ.
+    pub fn highest_of(&self, n: i32) -> Distribution {
+        Self::extreme_of(self.iter(), self.mass(), n)
+    }
+
+    /// Distribution of the lowest of `n` independent outcomes.
+    pub fn lowest_of(&self, n: i32) -> Distribution {
+        let outcomes = self.outcomes.iter().rev().map(|(&value, &p)| (value, p));
+        Self::extreme_of(outcomes, self.mass(), n)
+    }
+
+    /// Visits outcomes from the one that is least extreme, giving each the probability that
+    /// every outcome is at most that extreme, less the probability for the one before.
+    fn extreme_of(outcomes: impl Iterator<Item = (i64, f64)>, mass: f64, n: i32) -> Distribution {
+        let mut before: f64 = 0.0;
+        Self::from_weights(outcomes.map(|(value, p)| {
+            let through = before + p / mass;
+            let weight = through.powi(n) - before.powi(n);
+            before = through;
+            (value, weight)
+        }))
+    }
+
+    /// Solves `x = base + step ⊛ x` for a chain of rolls (e.g. an exploding die) that continues
+    /// at most `limit` times.
+    ///
+    /// The chain is followed until the remaining mass is negligible. If it is cut short by the
+    /// limit instead, the last roll counts whatever it shows.
+    pub fn chain(base: &Distribution, step: &Distribution, limit: usize) -> Distribution {
+        let mut result = base.clone();
+        let mut prefix = step.clone();
+
+        for depth in 0..limit {
+            if prefix.mass() < EPSILON || depth == MAX_DEPTH {
+                return result;
+            }
+            result.add_scaled(&prefix.convolve(base), 1.0);
+            prefix = prefix.convolve(step);
+        }
+
+        result.add_scaled(&prefix, 1.0);
+        result
+    }
+}
+
+/// Expected sum of the dice in positions `kept` when the outcomes of `dice` are sorted in
+/// ascending order.
+///
+/// Uses P(X₍ⱼ₎ ≥ v) = P(at least n-j of the dice show v or more), where the count of dice
+/// reaching v follows a Poisson binomial distribution.
+pub fn order_statistic_sum(dice: &[Distribution], kept: Range<usize>) -> f64 {
+    let n = dice.len();
+    let mut values: Vec<i64> = dice
+        .iter()
+        .flat_map(|d| d.outcomes.keys().copied())
+        .collect();
+    values.sort_unstable();
+    values.dedup();
+
+    // P(X₍ⱼ₎ ≥ v) for each kept position j, at the previously visited value.
+    let mut previous = vec![0.0; kept.len()];
+    let mut expected = 0.0;
+
+    for &value in values.iter().rev() {
+        let mut counts = vec![0.0; n + 1];
+        counts[0] = 1.0;
+        for die in dice {
+            let p = die.at_least(value) / die.mass();
+            for k in (0..n).rev() {
+                counts[k + 1] += counts[k] * p;
+                counts[k] *= 1.0 - p;
+            }
+        }
+
+        for (slot, j) in kept.clone().enumerate() {
+            let at_least: f64 = counts[n - j..].iter().sum();
+            expected += value as f64 * (at_least - previous[slot]);
+            previous[slot] = at_least;
+        }
+    }
+
+    expected
+}
+
+/// Distribution of the sum of the dice in positions `kept` when the outcomes are sorted in
+/// ascending order.
+///
+/// Dice come in classes of identical distributions, e.g. a first die rolled with advantage and
+/// the rest rolled plainly. Values are visited from highest to lowest; at each value, the
+/// number of each class's remaining dice that show exactly that value is binomial, conditioned
+/// on none of them being any higher.
+pub fn kept_sum(classes: &[(Distribution, usize)], kept: Range<usize>) -> Distribution {
+    let n: usize = classes.iter().map(|(_, count)| count).sum();
+    let mut values: Vec<i64> = classes
+        .iter()
+        .flat_map(|(d, _)| d.outcomes.keys().copied())
+        .collect();
+    values.sort_unstable_by(|a, b| b.cmp(a));
+    values.dedup();
+
+    let mut states: HashMap<(Vec<usize>, i64), f64> = HashMap::new();
+    states.insert((vec![0; classes.len()], 0), 1.0);
+
+    for &value in &values {
+        let mut next = HashMap::new();
+
+        for ((assigned, sum), p) in states {
+            // Each combination of how many of each class's remaining dice show this value.
+            let mut combinations = vec![(Vec::new(), 0, p)];
+            for ((distribution, count), &done) in classes.iter().zip(&assigned) {
+                let at_most = distribution.mass() - distribution.at_least(value + 1);
+                let q = match distribution.outcomes.get(&value) {
+                    Some(&p) if at_most > 0.0 => p / at_most,
+                    _ => 0.0,
+                };
+                let binomial = binomial(count - done, q);
+
+                combinations = combinations
+                    .into_iter()
+                    .flat_map(|(counts, total, p)| {
+                        binomial.iter().enumerate().map(move |(c, &b)| {
+                            let mut counts = counts.clone();
+                            counts.push(done + c);
+                            (counts, total + c, p * b)
+                        })
+                    })
+                    .filter(|&(_, _, p)| p > 0.0)
+                    .collect();
+            }
+
+            let start = assigned.iter().sum::<usize>();
+            for (counts, total, p) in combinations {
+                // These dice occupy descending positions start..start+total.
+                let positions = n - start - total..n - start;
+                let kept_count = positions.filter(|position| kept.contains(position)).count();
+                let sum = sum + value * kept_count as i64;
+                *next.entry((counts, sum)).or_default() += p;
+            }
+        }
+
+        states = next;
+    }
+
+    Distribution::from_weights(
+        states
+            .into_iter()
+            .filter(|((assigned, _), _)| assigned.iter().sum::<usize>() == n)
+            .map(|((_, sum), p)| (sum, p)),
+    )
+}
+
+/// Probability of each number of successes in `n` trials with probability `q`.
+fn binomial(n: usize, q: f64) -> Vec<f64> {
+    let mut coefficient = 1.0;
+    (0..=n)
+        .map(|k| {
+            let p = coefficient * q.powi(k as i32) * (1.0 - q).powi((n - k) as i32);
+            coefficient *= (n - k) as f64 / (k + 1) as f64;
+            p
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Distribution, kept_sum, order_statistic_sum};
+
+    fn die(max: i64) -> Distribution {
+        Distribution::from_weights((1..=max).map(|k| (k, 1.0 / max as f64)))
+    }
+
+    #[test]
+    fn convolve_two_dice() {
+        let d6 = die(6);
+        let sum = d6.convolve(&d6);
+        assert!((sum.at_least(7) - 21.0 / 36.0).abs() < 1e-10);
+        assert!((sum.at_least(12) - 1.0 / 36.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn convolve_spread_out_outcomes() {
+        let spread = Distribution::from_weights([(-1_000_000, 0.5), (1_000_000, 0.5)]);
+        let sum = spread.convolve(&die(6));
+        assert_eq!(sum, spread.combine(&die(6), |a, b| a + b));
+        assert!((sum.at_least(0) - 0.5).abs() < 1e-10);
+    }
+
+    #[test]
+    fn unreachable_value() {
+        let chance = die(20).at_least(25);
+        assert_eq!(chance, 0.0);
+        assert!(chance.is_sign_positive());
+    }
+
+    #[test]
+    fn chain_of_exploding_d6() {
+        // Explode on 6: mean of the chain is 3.5 / (1 - 1/6) = 4.2
+        let base = Distribution::from_weights((1..=5).map(|k| (k, 1.0 / 6.0)));
+        let step = Distribution::from_weights([(6, 1.0 / 6.0)]);
+        let chain = Distribution::chain(&base, &step, 100);
+        assert!((order_statistic_sum(&[chain], 0..1) - 4.2).abs() < 1e-10);
+    }
+
+    #[test]
+    fn chain_cut_short() {
+        // Exploding at most once: 3.5 + 3.5/6
+        let base = Distribution::from_weights((1..=5).map(|k| (k, 1.0 / 6.0)));
+        let step = Distribution::from_weights([(6, 1.0 / 6.0)]);
+        let chain = Distribution::chain(&base, &step, 1);
+        assert!((chain.mass() - 1.0).abs() < 1e-10);
+        assert!((chain.mean() - 3.5 * 7.0 / 6.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn highest_and_lowest_of_two_d20() {
+        let d20 = die(20);
+        assert!((d20.highest_of(2).mean() - 13.825).abs() < 1e-10);
+        assert!((d20.lowest_of(2).mean() - 7.175).abs() < 1e-10);
+        assert!((d20.highest_of(3).mass() - 1.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn kept_sum_of_4d6_keep_highest_3() {
+        let kept = kept_sum(&[(die(6), 4)], 1..4);
+        assert!((kept.mean() - 15869.0 / 1296.0).abs() < 1e-10);
+        assert!((kept.at_least(18) - 21.0 / 1296.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn kept_sum_with_distinct_classes() {
+        // Keeping the higher of a d4 and a d8
+        let kept = kept_sum(&[(die(4), 1), (die(8), 1)], 1..2);
+        let expected = order_statistic_sum(&[die(4), die(8)], 1..2);
+        assert!((kept.mean() - expected).abs() < 1e-10);
+        assert!((kept.at_least(5) - 0.5).abs() < 1e-10);
+    }
+
+    #[test]
+    fn order_statistics_of_two_d20() {
+        let d20 = die(20);
+        let dice = [d20.clone(), d20];
+        assert!((order_statistic_sum(&dice, 1..2) - 13.825).abs() < 1e-10);
+        assert!((order_statistic_sum(&dice, 0..1) - 7.175).abs() < 1e-10);
+    }
+}
@@ -1,12 +1,25 @@
-use std::{io, num};
+use std::{io, num, ops::Range};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("Unable to parse expression: {0}")]
+    #[error(transparent)]
     Expr(#[from] ExpressionError),
 
     #[error(transparent)]
     IO(#[from] std::io::Error),
+
+    #[error("Unable to read alias {0}: {1}")]
+    Alias(String, String),
+}
+
+impl Error {
+    /// The expression and the byte span within it where parsing failed, if known.
+    pub fn span(&self) -> Option<(&str, Range<usize>)> {
+        match self {
+            Error::Expr(ExpressionError::Syntax { text, span, .. }) => Some((text, span.clone())),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -14,9 +27,34 @@ pub enum ExpressionError {
     #[error("Unable to parse expression: {0}")]
     BadExpression(String),
 
+    #[error("Unable to parse expression: {message} at position {}", .span.start + 1)]
+    Syntax {
+        text: String,
+        span: Range<usize>,
+        message: &'static str,
+    },
+
     #[error("Bad integer: {0}; {1}")]
     BadInteger(String, num::ParseIntError),
 
+    #[error("Unknown die: {0}")]
+    UnknownDie(String),
+
+    #[error("Unknown variable: @{0}")]
+    UnknownVariable(String),
+
+    #[error("Too many dice: {0}; the limit is {1}")]
+    TooManyDice(i64, i64),
+
+    #[error("Too many faces: d{0}; the limit is {1}")]
+    TooManyFaces(i64, i64),
+
+    #[error("Result out of range")]
+    Overflow,
+
+    #[error("Unsupported expression: {0}; {1}")]
+    Unsupported(String, &'static str),
+
     #[error(transparent)]
     Io(#[from] io::Error),
 }
@@ -1,509 +1,5231 @@
-use std::cmp;
+use std::{
+    cmp,
+    collections::BTreeMap,
+    fmt, iter,
+    ops::{Range, RangeInclusive},
+};
 
 use owo_colors::OwoColorize;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 
 use crate::{
+    distribution::{self, Distribution},
     error::ExpressionError,
-    token::{ExplodeTokenExtractor, RerollTokenExtractor, TokenExtractor},
+    profile::Profile,
+    token::{self, Token},
 };
 
 pub type Result<T, E = ExpressionError> = std::result::Result<T, E>;
 
-pub struct ExpressionParser {
-    bounded_expression: Regex,
-    modifier_expression: Regex,
-    reroll: RerollTokenExtractor,
-    explode: ExplodeTokenExtractor,
-}
+#[derive(Default)]
+pub struct ExpressionParser;
 
 impl ExpressionParser {
     pub fn new() -> Self {
-        ExpressionParser {
-            bounded_expression: Regex::new(r#"^([Aa]|[Ss])?(\d+[Dd])?[Dd]?(\d+)"#).unwrap(),
-            modifier_expression: Regex::new(r#"([+-]\d+)"#).unwrap(),
-            reroll: Default::default(),
-            explode: Default::default(),
-        }
+        ExpressionParser
     }
 
     pub fn parse(&self, expr: &str) -> Result<Expression> {
-        let mut expression = Expression::default();
-
-        match self.bounded_expression.captures(expr) {
-            Some(captures) => {
-                if let Some(group) = captures.get(1) {
-                    expression.advantage = match group.as_str() {
-                        "A" | "a" => StrategyModifier::Advantage,
-                        "S" | "s" => StrategyModifier::Disadvantage,
-                        _ => unreachable!("Regex can't match this"),
-                    };
-                }
+        let mut tokens = Tokens::new(expr);
+        let repeat = self.parse_repeat(&mut tokens)?;
+        let root = self.parse_sum(&mut tokens)?;
+        let target = self.parse_target(&mut tokens)?;
+        let bands = self.parse_bands(&mut tokens)?;
 
-                if let Some(group) = captures.get(2) {
-                    let subexpr = group.as_str();
-                    let subexpr = &subexpr[..subexpr.len() - 1];
-                    expression.count = subexpr
-                        .parse()
-                        .map_err(|e| ExpressionError::BadInteger(subexpr.into(), e))?;
-                } else {
-                    expression.count = 1;
-                }
+        if !tokens.is_at_end() {
+            return Err(tokens.error(match (target, &bands) {
+                (_, Some(_)) => "expected the end of the expression after the bands",
+                (Some(_), None) => "expected the end of the expression after the target",
+                (None, None) => "expected an operator or the end of the expression",
+            }));
+        }
 
-                let max = captures
-                    .get(3)
-                    .ok_or_else(|| ExpressionError::BadExpression(expr.into()))?
-                    .as_str();
-                expression.max = max
-                    .parse()
-                    .map_err(|e| ExpressionError::BadInteger(max.into(), e))?;
+        Ok(Expression {
+            root,
+            target,
+            repeat,
+            bands,
+        })
+    }
+
+    /// repeat := (integer '#')?, how many times to roll the expression, e.g. the 6 in 6#4d6kh3
+    fn parse_repeat(&self, tokens: &mut Tokens) -> Result<Option<u32>> {
+        let (Some(Token::Number(count)), Some(Token::Symbol("#"))) =
+            (tokens.peek(), tokens.peek_at(1))
+        else {
+            return Ok(None);
+        };
+
+        let span = tokens.span();
+        let count = match count.parse() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                return Err(ExpressionError::Syntax {
+                    text: tokens.text.into(),
+                    span,
+                    message: "expected a number of times to roll, from 1",
+                });
             }
-            None => return Err(ExpressionError::BadExpression(expr.into())),
+        };
+        tokens.advance();
+        tokens.advance();
+        tokens.first = tokens.index;
+        Ok(Some(count))
+    }
+
+    /// target := ('vs' '-'? integer)?, a number the total must reach, e.g. d20+5 vs 15
+    fn parse_target(&self, tokens: &mut Tokens) -> Result<Option<i32>> {
+        if tokens.eat_word(&["vs"]).is_none() {
+            return Ok(None);
         }
 
-        if let Some(text) = self.modifier_expression.find(expr) {
-            expression.modifier = text
-                .as_str()
-                .parse()
-                .map_err(|e| ExpressionError::BadInteger(text.as_str().into(), e))?;
+        let sign = if tokens.eat_symbol("-") { -1 } else { 1 };
+        let Some(value) = tokens.eat_number() else {
+            return Err(tokens.error("expected a target number"));
+        };
+        Ok(Some(sign * parse_integer(value)?))
+    }
+
+    /// bands := ('bands' ('[' label (',' integer ':' label)+ ']')?)?, names for ranges of the
+    /// total, e.g. 2d6+1 bands[miss,7:weak hit,10:strong hit]
+    ///
+    /// On its own, bands gives the 6-, 7-9 and 10+ of a Powered by the Apocalypse move.
+    fn parse_bands(&self, tokens: &mut Tokens) -> Result<Option<Bands>> {
+        if tokens.eat_word(&["bands"]).is_none() {
+            return Ok(None);
+        }
+        if tokens.peek() != Some(Token::Symbol("[")) {
+            return Ok(Some(Bands::pbta()));
         }
 
-        expression.reroll = parse_threshold_token(&self.reroll, expr, 1)?.map(Reroll);
-        expression.explode =
-            parse_threshold_token(&self.explode, expr, expression.max)?.map(Explode);
+        let open = tokens.span();
+        let error = || ExpressionError::Syntax {
+            text: tokens.text.into(),
+            span: open.clone(),
+            message: "expected bands such as [miss,7:weak hit,10:strong hit]",
+        };
+        let is_name = |name: &str| name.split_whitespace().all(is_identifier);
 
-        Ok(expression)
-    }
-}
+        let text = tokens.enclosed("]")?;
+        let mut parts = text.split(',');
+        let lowest = parts.next().map(str::trim).unwrap_or_default();
+        let mut thresholds: Vec<(i32, String)> = Vec::new();
+        for part in parts {
+            let (from, name) = part.split_once(':').ok_or_else(error)?;
+            let from = from.trim().parse().map_err(|_| error())?;
+            let name = name.trim();
+            if name.is_empty() || !is_name(name) {
+                return Err(error());
+            }
+            if thresholds.last().is_some_and(|&(last, _)| last >= from) {
+                return Err(error());
+            }
+            thresholds.push((from, name.into()));
+        }
+        if lowest.is_empty() || !is_name(lowest) || thresholds.is_empty() {
+            return Err(error());
+        }
 
-impl Default for ExpressionParser {
-    fn default() -> Self {
-        ExpressionParser::new()
+        Ok(Some(Bands {
+            lowest: lowest.into(),
+            thresholds,
+        }))
     }
-}
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
-pub struct Expression {
-    count: i32,
-    max: i32,
-    modifier: i32,
-    advantage: StrategyModifier,
-    reroll: Option<Reroll>,
-    explode: Option<Explode>,
-}
+    /// sum := product (('+' | '-') product)*
+    fn parse_sum(&self, tokens: &mut Tokens) -> Result<Node> {
+        let mut lhs = self.parse_product(tokens)?;
 
-impl Expression {
-    fn reroll(&self, value: i32) -> bool {
-        self.reroll
-            .map(|x| x.should_reroll(value))
-            .unwrap_or_default()
+        loop {
+            let operator = if tokens.eat_symbol("+") {
+                Operator::Add
+            } else if tokens.eat_symbol("-") {
+                Operator::Subtract
+            } else {
+                return Ok(lhs);
+            };
+
+            let rhs = self.parse_product(tokens)?;
+            lhs = Node::binary(operator, lhs, rhs);
+        }
     }
 
-    fn explode(&self, value: i32) -> bool {
-        self.explode
-            .map(|x| x.should_explode(value))
-            .unwrap_or_default()
+    /// product := unary (('*' | '/' | '/^') unary)*
+    fn parse_product(&self, tokens: &mut Tokens) -> Result<Node> {
+        let mut lhs = self.parse_unary(tokens)?;
+
+        loop {
+            let operator = if tokens.eat_symbol("*") {
+                Operator::Multiply
+            } else if tokens.eat_symbol("/") {
+                Operator::Divide(Rounding::Down)
+            } else if tokens.eat_symbol("/^") {
+                Operator::Divide(Rounding::Up)
+            } else {
+                return Ok(lhs);
+            };
+
+            // A divisor with variables is checked once they're resolved.
+            let rhs = self.parse_unary(tokens)?;
+            if matches!(operator, Operator::Divide(_))
+                && !rhs.has_variables()
+                && rhs.bounds().contains(&0)
+            {
+                return Err(ExpressionError::Unsupported(
+                    tokens.text.into(),
+                    "divisor may be zero",
+                ));
+            }
+            lhs = Node::binary(operator, lhs, rhs);
+        }
     }
 
-    /// Expected value of a single die with reroll and explode, starting from a plain roll.
-    ///
-    /// Values ≤ r are rerolled; terminal values ≥ t explode (add value and roll again).
-    /// Derivation: E = (r+1+M)(M-r) / (2(t-1-r))
-    fn expected_plain(m: i32, r: i32, t: i32) -> f64 {
-        let denom = 2 * (t - 1 - r);
-        if denom > 0 {
-            (r + 1 + m) as f64 * (m - r) as f64 / denom as f64
+    /// unary := ('-' | '+') unary | primary tag?
+    fn parse_unary(&self, tokens: &mut Tokens) -> Result<Node> {
+        if tokens.eat_symbol("-") {
+            Ok(Node::Negate(Box::new(self.parse_unary(tokens)?)))
+        } else if tokens.eat_symbol("+") {
+            self.parse_unary(tokens)
         } else {
-            // No explode (t > m): uniform on [r+1, m]
-            (r + 1 + m) as f64 / 2.0
+            let node = self.parse_primary(tokens)?;
+            self.parse_tag(tokens, node)
         }
     }
 
-    /// Expected value of the first die, which may have advantage or disadvantage.
-    ///
-    /// The initial roll uses the strategy (max/min of two rolls), then reroll and explode
-    /// apply normally. Rerolls and explode continuations are always single rolls.
-    fn expected_first_die(m: i32, r: i32, t: i32, strategy: StrategyModifier, e_plain: f64) -> f64 {
-        match strategy {
-            StrategyModifier::Normal => e_plain,
-            _ => {
-                let m_sq = (m * m) as f64;
-                let mut e = 0.0;
-                for k in 1..=m {
-                    let weight = match strategy {
-                        StrategyModifier::Advantage => (2 * k - 1) as f64,
-                        StrategyModifier::Disadvantage => (2 * (m - k) + 1) as f64,
-                        StrategyModifier::Normal => unreachable!(),
-                    };
-                    let contribution = if k <= r {
-                        e_plain
-                    } else if k >= t {
-                        k as f64 + e_plain
-                    } else {
-                        k as f64
+    /// tag := '[' label ']', naming the kind of a term, e.g. the fire in 2d6[fire]
+    fn parse_tag(&self, tokens: &mut Tokens, node: Node) -> Result<Node> {
+        if tokens.peek() != Some(Token::Symbol("[")) {
+            return Ok(node);
+        }
+
+        let open = tokens.span();
+        let label = tokens.enclosed("]")?.trim();
+        if !is_identifier(label) {
+            return Err(ExpressionError::Syntax {
+                text: tokens.text.into(),
+                span: open,
+                message: "expected a label such as [fire]",
+            });
+        }
+        Ok(Node::Tagged(label.into(), Box::new(node)))
+    }
+
+    /// primary := strategy? ('(' sum ')' clamp* | dice) | function | variable | integer
+    fn parse_primary(&self, tokens: &mut Tokens) -> Result<Node> {
+        // Advantage before a parenthesised expression applies to its total, a(2d6+3); before
+        // dice, it applies to the first die only.
+        if let Some((strategy, rolls)) = self.parse_strategy(tokens) {
+            if !tokens.eat_symbol("(") {
+                let mut dice = self.parse_dice(tokens)?;
+                dice.advantage = strategy;
+                dice.extra_rolls = rolls - 2;
+                return Ok(Node::Dice(dice));
+            }
+            let node = self.parse_group(tokens)?;
+            return self.parse_total_clamp(tokens, Node::Strategy(strategy, rolls, Box::new(node)));
+        }
+
+        if tokens.eat_symbol("(") {
+            let node = self.parse_group(tokens)?;
+            return self.parse_total_clamp(tokens, node);
+        }
+
+        if tokens.eat_symbol("{") {
+            let node = self.parse_braces(tokens)?;
+            return self.parse_total_clamp(tokens, node);
+        }
+
+        match tokens.peek() {
+            Some(Token::Number(_)) if tokens.peek_at(1) == Some(Token::Word("wod")) => {
+                Ok(Node::Dice(self.parse_wod(tokens)?))
+            }
+            Some(Token::Number(_)) if tokens.peek_at(1) == Some(Token::Word("sr")) => {
+                Ok(Node::Dice(self.parse_shadowrun(tokens)?))
+            }
+            Some(Token::Word("sw")) => self.parse_savage(tokens),
+            Some(Token::Word("bitd")) => Ok(Node::Dice(self.parse_blades(tokens)?)),
+            Some(Token::Number(number)) => {
+                // A bare number leading the expression is shorthand for a single die (20 => 1d20,
+                // 20+5 => 1d20+5), unless it's multiplied or divided, or other dice are added to
+                // it: in 5*2d6 or 3+2d6, it's a constant.
+                let is_dice = matches!(tokens.peek_at(1), Some(Token::Word("d" | "D")));
+                let is_die_shorthand = tokens.index == tokens.first
+                    && match tokens.peek_at(1) {
+                        Some(Token::Symbol("*" | "/" | "/^")) => false,
+                        Some(Token::Symbol("+" | "-")) => !tokens.has_dice_ahead(),
+                        _ => true,
                     };
-                    e += weight * contribution;
+                if is_dice || is_die_shorthand {
+                    Ok(Node::Dice(self.parse_dice(tokens)?))
+                } else {
+                    tokens.advance();
+                    Ok(Node::Constant(parse_integer(number)?))
                 }
-                e / m_sq
             }
+            Some(Token::Word("d" | "D")) => Ok(Node::Dice(self.parse_dice(tokens)?)),
+            Some(Token::Variable(name)) => {
+                tokens.advance();
+                Ok(Node::Variable(name.into()))
+            }
+            Some(Token::Word(name @ ("max" | "min" | "abs" | "floor" | "ceil")))
+                if tokens.peek_at(1) == Some(Token::Symbol("(")) =>
+            {
+                tokens.advance();
+                tokens.advance();
+                self.parse_function(tokens, name)
+            }
+            _ => Err(tokens.error("expected a number, dice, '(' or '{'")),
         }
     }
 
-    pub fn average_result(&self) -> f64 {
-        if self.count <= 0 {
-            return self.modifier as f64;
+    /// function := ('max' | 'min' | 'abs' | 'floor' | 'ceil') '(' sum (',' sum)* ')'
+    ///
+    /// Every other value is a whole number, so floor and ceil take a single division and set
+    /// which way it rounds: floor(3d6/2) is 3d6/2, and ceil(3d6/2) is 3d6/^2.
+    fn parse_function(&self, tokens: &mut Tokens, name: &str) -> Result<Node> {
+        let is_variadic = matches!(name, "max" | "min");
+        let mut args = vec![self.parse_sum(tokens)?];
+        while is_variadic && tokens.eat_symbol(",") {
+            args.push(self.parse_sum(tokens)?);
         }
 
-        let m = self.max;
-        let r = self.reroll.map_or(0, |reroll| reroll.0);
-        let t = self.explode.map_or(m + 1, |explode| explode.0);
+        if !tokens.eat_symbol(")") {
+            return Err(tokens.error("expected ')'"));
+        }
 
-        let e_plain = Self::expected_plain(m, r, t);
-        let e_first = Self::expected_first_die(m, r, t, self.advantage, e_plain);
+        let function = match name {
+            "max" => Function::Max,
+            "min" => Function::Min,
+            "abs" => Function::Abs,
+            _ => {
+                let rounding = match name {
+                    "ceil" => Rounding::Up,
+                    _ => Rounding::Down,
+                };
+                return match args.swap_remove(0) {
+                    Node::Binary(Operator::Divide(_), lhs, rhs) => {
+                        Ok(Node::Binary(Operator::Divide(rounding), lhs, rhs))
+                    }
+                    _ => Err(ExpressionError::Unsupported(
+                        tokens.text.into(),
+                        "floor and ceil round a single division, such as floor(3d6/2)",
+                    )),
+                };
+            }
+        };
+        Ok(Node::Function(function, args))
+    }
 
-        e_first + (self.count - 1) as f64 * e_plain + self.modifier as f64
+    /// The rest of a parenthesised expression, after the opening parenthesis.
+    fn parse_group(&self, tokens: &mut Tokens) -> Result<Node> {
+        let node = self.parse_sum(tokens)?;
+        if !tokens.eat_symbol(")") {
+            return Err(tokens.error("expected ')'"));
+        }
+        Ok(node)
     }
-}
 
-#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub enum StrategyModifier {
-    Advantage,
-    Disadvantage,
-    #[default]
-    Normal,
-}
+    /// braces := '{' sum (',' sum)* '}' keep_drop?
+    ///
+    /// With several members, keep/drop applies to their totals ({4d6,4d6,4d6}kh1). With one
+    /// member, it applies to the dice added together inside ({2d6+1d8}kh2).
+    fn parse_braces(&self, tokens: &mut Tokens) -> Result<Node> {
+        let mut members = vec![self.parse_sum(tokens)?];
+        while tokens.eat_symbol(",") {
+            members.push(self.parse_sum(tokens)?);
+        }
+        if !tokens.eat_symbol("}") {
+            return Err(tokens.error("expected ',' or '}'"));
+        }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct Reroll(i32);
+        let keep_drop = self.parse_keep_drop(tokens)?;
+        match (&*members, keep_drop) {
+            ([member], Some(keep_drop)) => {
+                let mut dice = Vec::new();
+                if !member.collect_pooled(&mut dice) {
+                    return Err(ExpressionError::Unsupported(
+                        tokens.text.into(),
+                        "keep/drop on one member applies to its dice, so it can only add dice",
+                    ));
+                }
+                Ok(Node::Pooled(dice, keep_drop))
+            }
+            _ => Ok(Node::Group(members, keep_drop)),
+        }
+    }
 
-impl Reroll {
-    fn should_reroll(self, value: i32) -> bool {
-        self.0 >= value
+    /// keep_drop := ('k' | 'kh' | 'kl' | 'dh' | 'dl') integer?
+    fn parse_keep_drop(&self, tokens: &mut Tokens) -> Result<Option<KeepDrop>> {
+        let Some(word) = tokens.eat_word(&["k", "kh", "kl", "dh", "dl"]) else {
+            return Ok(None);
+        };
+        let count = tokens.eat_number().map(parse_integer).transpose()?;
+        let count = count.unwrap_or(1);
+        Ok(Some(match word {
+            "k" | "kh" => KeepDrop::KeepHighest(count),
+            "kl" => KeepDrop::KeepLowest(count),
+            "dh" => KeepDrop::DropHighest(count),
+            _ => KeepDrop::DropLowest(count),
+        }))
     }
-}
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct Explode(i32);
+    /// Wraps a parenthesised expression in any clamps that follow it, e.g. (1d6-3)min0.
+    fn parse_total_clamp(&self, tokens: &mut Tokens, node: Node) -> Result<Node> {
+        let mut clamp = None;
+        while let Some(Token::Word("min" | "max")) = tokens.peek() {
+            self.parse_clamp(tokens, clamp.get_or_insert_default())?;
+        }
 
-impl Explode {
-    fn should_explode(self, value: i32) -> bool {
-        value >= self.0
+        match clamp {
+            Some(clamp) => Ok(Node::Clamp(clamp, Box::new(node))),
+            None => Ok(node),
+        }
     }
-}
 
-pub trait Realizer {
-    fn next(&mut self, max: i32) -> i32;
+    /// clamp := ('min' | 'max') '-'? integer, each at most once
+    fn parse_clamp(&self, tokens: &mut Tokens, clamp: &mut Clamp) -> Result<()> {
+        let bound = match tokens.peek() {
+            Some(Token::Word("min")) => &mut clamp.min,
+            _ => &mut clamp.max,
+        };
+        if bound.is_some() {
+            return Err(tokens.error("expected at most one minimum and one maximum"));
+        }
+        tokens.advance();
 
-    fn realize(&mut self, expression: &Expression) -> RealizedExpression {
-        let mut results = SmallVec::new();
-        let mut advantage = Some(expression.advantage);
+        let sign = if tokens.eat_symbol("-") { -1 } else { 1 };
+        let Some(value) = tokens.eat_number() else {
+            return Err(tokens.error("expected a number"));
+        };
+        *bound = Some(sign * parse_integer(value)?);
 
-        for _ in 0..expression.count {
-            let mut value = match advantage.take().unwrap_or_default() {
-                StrategyModifier::Advantage => {
-                    cmp::max(self.next(expression.max), self.next(expression.max))
-                }
-                StrategyModifier::Disadvantage => {
-                    cmp::min(self.next(expression.max), self.next(expression.max))
+        if let Clamp {
+            min: Some(min),
+            max: Some(max),
+        } = *clamp
+            && min > max
+        {
+            return Err(ExpressionError::Unsupported(
+                tokens.text.into(),
+                "the minimum is above the maximum",
+            ));
+        }
+        Ok(())
+    }
+
+    /// strategy := 'a'+ | 's'+
+    ///
+    /// Returns the strategy and how many times to roll: twice for a single letter, and once
+    /// more for each repeat (aad20 is the best of three).
+    fn parse_strategy(&self, tokens: &mut Tokens) -> Option<(StrategyModifier, i32)> {
+        let (strategy, letters): (_, &[&str]) = match tokens.peek() {
+            Some(Token::Word("a" | "A")) => (StrategyModifier::Advantage, &["a", "A"]),
+            Some(Token::Word("s" | "S")) => (StrategyModifier::Disadvantage, &["s", "S"]),
+            _ => return None,
+        };
+
+        let mut rolls = 1;
+        while tokens.eat_word(letters).is_some() {
+            rolls += 1;
+        }
+        Some((strategy, rolls))
+    }
+
+    /// dice := (count? 'd')? size modifier*
+    fn parse_dice(&self, tokens: &mut Tokens) -> Result<Dice> {
+        let mut dice = Dice {
+            count: 1,
+            ..Default::default()
+        };
+
+        // Either a count followed by d and a size, or a number that is the size itself (a20).
+        match tokens.eat_number() {
+            Some(number) if tokens.eat_word(&["d", "D"]).is_some() => {
+                dice.count = parse_integer(number)?;
+                dice.die = self.parse_size(tokens)?;
+            }
+            Some(number) => dice.die = Die::Standard(parse_integer(number)?),
+            None => {
+                if tokens.eat_word(&["d", "D"]).is_none() {
+                    return Err(tokens.error("expected dice, such as 2d6"));
                 }
-                StrategyModifier::Normal => self.next(expression.max),
-            };
+                dice.die = self.parse_size(tokens)?;
+            }
+        }
 
-            loop {
-                // If the value is small enough to re-roll, do not store it.
-                if expression.reroll(value) {
-                    value = self.next(expression.max);
-                    continue;
+        self.parse_modifiers(tokens, &mut dice)?;
+
+        // A named die is checked once its faces are known.
+        if !matches!(dice.die, Die::Named(_))
+            && let Some(problem) = dice.validate()
+        {
+            return Err(ExpressionError::Unsupported(tokens.text.into(), problem));
+        }
+        Ok(dice)
+    }
+
+    /// wod := integer 'wod' integer? 'r'?
+    ///
+    /// A World of Darkness pool of d10s, each succeeding on 8 or more and rolling again on 10,
+    /// e.g. 7wod. A number after wod rolls again on 9 or 8 instead (7wod8), and r makes the roll
+    /// rote, rerolling each failure once. A pool of 0 rolls a chance die, which succeeds only on
+    /// 10.
+    fn parse_wod(&self, tokens: &mut Tokens) -> Result<Dice> {
+        let count = tokens.eat_number().map(parse_integer).transpose()?;
+        let count = count.unwrap_or_default();
+        tokens.eat_word(&["wod"]);
+
+        let again = match tokens.eat_number().map(parse_integer).transpose()? {
+            Some(again @ 8..=10) => again,
+            Some(_) => return Err(tokens.error("expected 8, 9 or 10 to roll again")),
+            None => 10,
+        };
+        let is_rote = tokens.eat_word(&["r"]).is_some();
+
+        let (count, system, success) = match count {
+            0 => (1, System::Chance, 10),
+            count => (count, System::Wod, 8),
+        };
+        Ok(Dice {
+            count,
+            die: Die::Standard(10),
+            reroll: is_rote.then_some(Reroll {
+                condition: Comparison::Less(success),
+                once: true,
+            }),
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(again))),
+            pool: Some(Pool {
+                success: Comparison::GreaterOrEqual(success),
+                failure: None,
+            }),
+            system: Some(system),
+            ..Default::default()
+        })
+    }
+
+    /// shadowrun := integer 'sr' '!'?
+    ///
+    /// A Shadowrun pool of d6s, where each 5 or 6 is a hit, e.g. 12sr. With edge (12sr!), 6s
+    /// roll again.
+    fn parse_shadowrun(&self, tokens: &mut Tokens) -> Result<Dice> {
+        let count = tokens.eat_number().map(parse_integer).transpose()?;
+        tokens.eat_word(&["sr"]);
+        let has_edge = tokens.eat_symbol("!");
+
+        Ok(Dice {
+            count: count.unwrap_or_default(),
+            die: Die::Standard(6),
+            explode: has_edge.then_some(Explode::Standard(Comparison::GreaterOrEqual(6))),
+            pool: Some(Pool {
+                success: Comparison::GreaterOrEqual(5),
+                failure: None,
+            }),
+            system: Some(System::Shadowrun),
+            ..Default::default()
+        })
+    }
+
+    /// savage := 'sw' integer
+    ///
+    /// A Savage Worlds trait roll, e.g. sw8: a trait die and a d6 wild die, each rolling again
+    /// on its highest face, keeping the higher.
+    fn parse_savage(&self, tokens: &mut Tokens) -> Result<Node> {
+        tokens.eat_word(&["sw"]);
+        let Some(size) = tokens.eat_number() else {
+            return Err(tokens.error("expected a trait die size, such as 8"));
+        };
+
+        let die = |max| Dice {
+            count: 1,
+            die: Die::Standard(max),
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(max))),
+            system: Some(System::SavageWorlds),
+            ..Default::default()
+        };
+        let trait_die = die(parse_integer(size)?);
+        if let Some(problem) = trait_die.validate() {
+            return Err(ExpressionError::Unsupported(tokens.text.into(), problem));
+        }
+        Ok(Node::Function(
+            Function::Max,
+            vec![Node::Dice(trait_die), Node::Dice(die(6))],
+        ))
+    }
+
+    /// blades := 'bitd' integer
+    ///
+    /// A Blades in the Dark action roll, e.g. bitd3: the highest of three d6s. With no dice
+    /// (bitd0), the lowest of two.
+    fn parse_blades(&self, tokens: &mut Tokens) -> Result<Dice> {
+        tokens.eat_word(&["bitd"]);
+        let Some(count) = tokens.eat_number() else {
+            return Err(tokens.error("expected a number of dice, from 0"));
+        };
+
+        let (count, keep_drop, system) = match parse_integer(count)? {
+            0 => (2, KeepDrop::KeepLowest(1), System::BladesZero),
+            count => (count, KeepDrop::KeepHighest(1), System::Blades),
+        };
+        Ok(Dice {
+            count,
+            die: Die::Standard(6),
+            keep_drop: Some(keep_drop),
+            system: Some(system),
+            ..Default::default()
+        })
+    }
+
+    /// size := integer | 'F' | '%' | '{' faces '}'
+    fn parse_size(&self, tokens: &mut Tokens) -> Result<Die> {
+        match tokens.peek() {
+            Some(Token::Number(max)) => {
+                tokens.advance();
+                Ok(Die::Standard(parse_integer(max)?))
+            }
+            Some(Token::Word("f" | "F")) => {
+                tokens.advance();
+                Ok(Die::Fudge)
+            }
+            Some(Token::Symbol("%")) => {
+                tokens.advance();
+                Ok(Die::Percentile)
+            }
+            Some(Token::Symbol("{")) => {
+                let open = tokens.span();
+                let table = tokens.enclosed("}")?;
+                // A lone name is the name of a stored die, never a die with one named face.
+                if is_identifier(table) {
+                    Ok(Die::Named(table.into()))
+                } else {
+                    let faces = Face::parse_table(table).map_err(|e| match e {
+                        ExpressionError::BadExpression(_) => ExpressionError::Syntax {
+                            text: tokens.text.into(),
+                            span: open,
+                            message: "expected faces such as {1,2,3} or {miss,hit=1}",
+                        },
+                        e => e,
+                    })?;
+                    Ok(Die::Custom(faces))
                 }
+            }
+            _ => Err(tokens.error("expected a die size, such as 6, F, % or {1,2,3}")),
+        }
+    }
 
-                // Store value.
-                results.push(value);
+    /// modifier := reroll | explode | keep/drop | pool | clamp, each at most once
+    fn parse_modifiers(&self, tokens: &mut Tokens, dice: &mut Dice) -> Result<()> {
+        // A named die's faces aren't known until it's resolved, so there's no default threshold.
+        let (min, max) = match dice.die {
+            Die::Named(_) => (None, None),
+            _ => (Some(dice.die.min()), Some(dice.die.max())),
+        };
 
-                // If the value is large enough to explode, roll another and continue.
-                if expression.explode(value) {
-                    value = self.next(expression.max);
-                    continue;
+        loop {
+            match tokens.peek() {
+                Some(Token::Word(word @ ("r" | "ro"))) => {
+                    if dice.reroll.is_some() {
+                        return Err(tokens.error("expected at most one reroll"));
+                    }
+                    tokens.advance();
+                    let condition = self.parse_condition(tokens, Comparison::LessOrEqual, min)?;
+                    dice.reroll = Some(Reroll {
+                        condition,
+                        once: word == "ro",
+                    });
                 }
+                Some(Token::Symbol(token @ ("!" | "!!" | "!p")) | Token::Word(token @ "e")) => {
+                    if dice.explode.is_some() {
+                        return Err(tokens.error("expected at most one explosion"));
+                    }
+                    tokens.advance();
+                    let condition =
+                        self.parse_condition(tokens, Comparison::GreaterOrEqual, max)?;
+                    dice.explode = Some(match token {
+                        "!!" => Explode::Compound(condition),
+                        "!p" => Explode::Penetrate(condition),
+                        _ => Explode::Standard(condition),
+                    });
+                }
+                Some(Token::Word("k" | "kh" | "kl" | "dh" | "dl")) => {
+                    if dice.keep_drop.is_some() {
+                        return Err(tokens.error("expected at most one keep or drop"));
+                    }
+                    dice.keep_drop = self.parse_keep_drop(tokens)?;
+                }
+                Some(Token::Symbol(">=" | "<=" | ">" | "<" | "=")) => {
+                    if dice.pool.is_some() {
+                        return Err(tokens.error("expected at most one success threshold"));
+                    }
+                    let success = self.parse_comparison(tokens)?;
 
-                break;
+                    // The comparison is optional for failures; f1 means values of 1 or less.
+                    let failure = match tokens.eat_word(&["f", "F"]) {
+                        Some(_) => {
+                            Some(self.parse_condition(tokens, Comparison::LessOrEqual, None)?)
+                        }
+                        None => None,
+                    };
+                    dice.pool = Some(Pool { success, failure });
+                }
+                Some(Token::Word("min" | "max")) => {
+                    self.parse_clamp(tokens, dice.clamp.get_or_insert_default())?;
+                }
+                Some(Token::Word(word @ ("c" | "cf"))) => {
+                    let range = match word {
+                        "c" => &mut dice.critical,
+                        _ => &mut dice.fumble,
+                    };
+                    if range.is_some() {
+                        return Err(
+                            tokens.error("expected at most one critical and one fumble range")
+                        );
+                    }
+                    let span = tokens.span();
+                    tokens.advance();
+                    *range = Some(match word {
+                        "c" => self.parse_condition(tokens, Comparison::GreaterOrEqual, max)?,
+                        _ => self.parse_condition(tokens, Comparison::LessOrEqual, min)?,
+                    });
+
+                    // A named die's range is checked once its faces are known.
+                    if !matches!(dice.die, Die::Named(_))
+                        && let Some(message) = dice.range_problem()
+                    {
+                        return Err(ExpressionError::Syntax {
+                            text: tokens.text.into(),
+                            span,
+                            message,
+                        });
+                    }
+                }
+                _ => break,
             }
         }
 
-        RealizedExpression {
-            results,
-            max: expression.max,
-            modifier: expression.modifier,
+        if dice.keep_drop.is_some() && dice.pool.is_some() {
+            return Err(ExpressionError::Unsupported(
+                tokens.text.into(),
+                "keep/drop can't be combined with success counting",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// comparison := ('>=' | '<=' | '>' | '<' | '=') integer
+    fn parse_comparison(&self, tokens: &mut Tokens) -> Result<Comparison> {
+        let Some(op) = tokens.eat_comparison() else {
+            return Err(tokens.error("expected a comparison, such as >=8"));
+        };
+        match tokens.eat_number() {
+            Some(value) => Ok(Comparison::new(op, parse_integer(value)?)),
+            None => Err(tokens.error("expected a number")),
+        }
+    }
+
+    /// Parses a reroll or explode condition. A bare number is compared the token's usual way
+    /// (r2 rerolls values ≤ 2), and a token on its own takes the default threshold, if there
+    /// is one.
+    fn parse_condition(
+        &self,
+        tokens: &mut Tokens,
+        bare: fn(i32) -> Comparison,
+        default: Option<i32>,
+    ) -> Result<Comparison> {
+        if matches!(
+            tokens.peek(),
+            Some(Token::Symbol(">=" | "<=" | ">" | "<" | "="))
+        ) {
+            return self.parse_comparison(tokens);
+        }
+
+        match tokens.eat_number() {
+            Some(value) => Ok(bare(parse_integer(value)?)),
+            None => default.map(bare).ok_or_else(|| {
+                ExpressionError::Unsupported(
+                    tokens.text.into(),
+                    "this die needs an explicit threshold",
+                )
+            }),
         }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct RealizedExpression {
-    results: SmallVec<[i32; 4]>,
-    max: i32,
-    modifier: i32,
+/// The tokens of an expression being parsed, and the position of the next one.
+struct Tokens<'a> {
+    text: &'a str,
+    tokens: Vec<(Token<'a>, Range<usize>)>,
+    index: usize,
+    /// The index of the first token after any repeat count, where 20 is shorthand for 1d20
+    first: usize,
 }
 
-impl RealizedExpression {
-    pub fn sum(&self) -> i32 {
-        let result: i32 = self.results.iter().sum();
-        result + self.modifier
+impl<'a> Tokens<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            tokens: token::tokenize(text),
+            index: 0,
+            first: 0,
+        }
     }
 
-    pub fn modifier(&self) -> i32 {
-        self.modifier
+    fn is_at_end(&self) -> bool {
+        self.index == self.tokens.len()
     }
 
-    pub fn results(&'_ self) -> impl Iterator<Item = (Highlight, i32)> + '_ {
-        self.results.iter().map(move |&x| match x {
-            1 => (Highlight::Low, 1),
-            x if x == self.max => (Highlight::High, x),
-            x => (Highlight::Normal, x),
+    fn peek(&self) -> Option<Token<'a>> {
+        self.peek_at(0)
+    }
+
+    /// Whether any dice come after the next token, e.g. the 2d6 in 3+2d6.
+    fn has_dice_ahead(&self) -> bool {
+        self.tokens.iter().skip(self.index + 1).any(|(token, _)| {
+            matches!(
+                token,
+                Token::Word("d" | "D" | "a" | "A" | "s" | "S" | "wod" | "sr" | "sw" | "bitd")
+            )
         })
     }
 
-    pub fn is_critical(&self) -> bool {
-        self.results.len() == 1 && self.sum() == self.max + self.modifier
+    fn peek_at(&self, offset: usize) -> Option<Token<'a>> {
+        self.tokens
+            .get(self.index + offset)
+            .map(|(token, _)| *token)
+    }
+
+    fn advance(&mut self) {
+        self.index = cmp::min(self.index + 1, self.tokens.len());
+    }
+
+    /// Span of the next token, or an empty span at the end of the text.
+    fn span(&self) -> Range<usize> {
+        match self.tokens.get(self.index) {
+            Some((_, span)) => span.clone(),
+            None => self.text.len()..self.text.len(),
+        }
+    }
+
+    fn error(&self, message: &'static str) -> ExpressionError {
+        ExpressionError::Syntax {
+            text: self.text.into(),
+            span: self.span(),
+            message,
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let is_match = self.peek() == Some(Token::Symbol(symbol));
+        if is_match {
+            self.advance();
+        }
+        is_match
+    }
+
+    fn eat_word(&mut self, words: &[&str]) -> Option<&'a str> {
+        match self.peek() {
+            Some(Token::Word(word)) if words.contains(&word) => {
+                self.advance();
+                Some(word)
+            }
+            _ => None,
+        }
+    }
+
+    fn eat_number(&mut self) -> Option<&'a str> {
+        match self.peek() {
+            Some(Token::Number(number)) => {
+                self.advance();
+                Some(number)
+            }
+            _ => None,
+        }
+    }
+
+    fn eat_comparison(&mut self) -> Option<&'a str> {
+        match self.peek() {
+            Some(Token::Symbol(op @ (">=" | "<=" | ">" | "<" | "="))) => {
+                self.advance();
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    /// Consumes an opening token and everything up to its closing one, returning the raw text
+    /// in between (e.g. the faces in d{miss,hit=1}).
+    fn enclosed(&mut self, close: &str) -> Result<&'a str> {
+        let start = self.span().end;
+        self.advance();
+
+        while !self.is_at_end() {
+            if self.eat_symbol(close) {
+                let end = self.tokens[self.index - 1].1.start;
+                return Ok(&self.text[start..end]);
+            }
+            self.advance();
+        }
+
+        Err(self.error(match close {
+            "]" => "expected a closing bracket",
+            _ => "expected a closing brace",
+        }))
     }
 }
 
-impl From<RealizedExpression> for comfy_table::Row {
-    fn from(value: RealizedExpression) -> Self {
-        use std::fmt::Write;
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Expression {
+    root: Node,
+    /// The number the total must reach to pass, e.g. the 15 in d20+5 vs 15
+    #[serde(default)]
+    target: Option<i32>,
+    /// How many times to roll, e.g. the 6 in 6#4d6kh3
+    #[serde(default)]
+    repeat: Option<u32>,
+    /// Names for ranges of the total, e.g. a miss, weak hit or strong hit
+    #[serde(default)]
+    bands: Option<Bands>,
+}
+
+impl Expression {
+    /// Replaces references to profile definitions (such as named dice) with their current
+    /// values. Expressions must be resolved before they are rolled or averaged.
+    pub fn resolve(&self, profile: &Profile) -> Result<Expression> {
+        let count = self
+            .root
+            .dice_count()
+            .saturating_mul(self.repetitions() as i64);
+        if count > profile.limits.max_dice {
+            return Err(ExpressionError::TooManyDice(count, profile.limits.max_dice));
+        }
+
+        Ok(Expression {
+            root: self.root.resolve(profile)?,
+            target: self.target,
+            repeat: self.repeat,
+            bands: self.bands.clone(),
+        })
+    }
+
+    /// How many times the expression is rolled, once unless it starts with a count like 6#.
+    pub fn repetitions(&self) -> u32 {
+        self.repeat.unwrap_or(1)
+    }
+
+    pub fn target(&self) -> Option<i32> {
+        self.target
+    }
+
+    pub fn has_bands(&self) -> bool {
+        self.bands.is_some()
+    }
+
+    /// Uses `target` (e.g. from --dc) unless the expression has a target of its own.
+    pub fn or_target(self, target: Option<i32>) -> Self {
+        Expression {
+            target: self.target.or(target),
+            ..self
+        }
+    }
+
+    pub fn average_result(&self) -> f64 {
+        self.root.average_result()
+    }
+
+    /// The exact probability that the total reaches the target, if there is one.
+    pub fn success_chance(&self) -> Option<f64> {
+        let target = self.target?;
+        self.chances_at_least(&[target as i64]).pop()
+    }
+
+    /// The exact probability of the total landing in each band, if there are any.
+    pub fn band_chances(&self) -> Option<Vec<(&str, f64)>> {
+        let bands = self.bands.as_ref()?;
+
+        // Each band is what reaches its own threshold but not the next.
+        let thresholds: Vec<_> = bands
+            .thresholds
+            .iter()
+            .map(|&(from, _)| from as i64)
+            .collect();
+        let reached = self.chances_at_least(&thresholds);
+        let lower = iter::once(1.0).chain(reached.iter().copied());
+        let upper = reached.iter().copied().chain([0.0]);
+        let chances = lower.zip(upper).map(|(lower, upper)| lower - upper);
+        Some(bands.names().zip(chances).collect())
+    }
+
+    /// The probability that the total reaches each of `values`, worked out directly for a single
+    /// plain die and from the total's distribution otherwise.
+    fn chances_at_least(&self, values: &[i64]) -> Vec<f64> {
+        if let Node::Dice(dice) = &self.root
+            && let Some(chances) = values.iter().map(|&v| dice.chance_at_least(v)).collect()
+        {
+            return chances;
+        }
+
+        let distribution = self.root.distribution();
+        let mass = distribution.mass();
+        values
+            .iter()
+            .map(|&v| distribution.at_least(v) / mass)
+            .collect()
+    }
+
+    /// The chance of each outcome, for a roll judged only by its outcome, e.g. bitd3.
+    pub fn outcome_chances(&self) -> Option<Vec<(Outcome, f64)>> {
+        match &self.root {
+            Node::Dice(dice) => dice.outcome_chances(),
+            _ => None,
+        }
+    }
+}
+
+/// An expression as stored before expressions had more than one term: a group of dice that may
+/// be rolled with advantage, reroll low values or explode on high ones, plus a modifier.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LegacyExpression {
+    count: i32,
+    max: i32,
+    modifier: i32,
+    advantage: StrategyModifier,
+    /// Rerolls values up to this one
+    reroll: Option<i32>,
+    /// Explodes on values from this one
+    explode: Option<i32>,
+}
+
+impl TryFrom<LegacyExpression> for Expression {
+    type Error = ExpressionError;
+
+    fn try_from(legacy: LegacyExpression) -> Result<Self> {
+        let dice = Dice {
+            count: legacy.count,
+            die: Die::Standard(legacy.max),
+            advantage: legacy.advantage,
+            reroll: legacy.reroll.map(|n| Reroll {
+                condition: Comparison::LessOrEqual(n),
+                once: false,
+            }),
+            explode: legacy
+                .explode
+                .map(|n| Explode::Standard(Comparison::GreaterOrEqual(n))),
+            ..Default::default()
+        };
+        if let Some(problem) = dice.validate() {
+            let text = format!("{}d{}", legacy.count, legacy.max);
+            return Err(ExpressionError::Unsupported(text, problem));
+        }
+
+        let root = match legacy.modifier {
+            0 => Node::Dice(dice),
+            n => Node::binary(Operator::Add, Node::Dice(dice), Node::Constant(n)),
+        };
+        Ok(Expression {
+            root,
+            target: None,
+            repeat: None,
+            bands: None,
+        })
+    }
+}
+
+/// Names for ranges of totals, e.g. the 6-, 7-9 and 10+ of a Powered by the Apocalypse move.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Bands {
+    /// The name for totals below the first threshold, e.g. miss
+    lowest: String,
+    /// The lowest total of each band above it with its name, in increasing order
+    thresholds: Vec<(i32, String)>,
+}
+
+impl Bands {
+    fn pbta() -> Self {
+        Bands {
+            lowest: "miss".into(),
+            thresholds: vec![(7, "weak hit".into()), (10, "strong hit".into())],
+        }
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        let rest = self.thresholds.iter().map(|(_, name)| name.as_str());
+        iter::once(self.lowest.as_str()).chain(rest)
+    }
+
+    /// The band a total lands in, highlighting the lowest as a failure and highest as a success.
+    fn band(&self, total: i64) -> (Highlight, &str) {
+        let index = self
+            .thresholds
+            .iter()
+            .take_while(|&&(from, _)| total >= from as i64)
+            .count();
+        let highlight = match index {
+            0 => Highlight::Failure,
+            n if n == self.thresholds.len() => Highlight::Success,
+            _ => Highlight::Normal,
+        };
+        (highlight, self.names().nth(index).unwrap_or_default())
+    }
+}
+
+/// A node in the expression tree.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Node {
+    Constant(i32),
+    /// A value stored in the profile, e.g. @str, which must be resolved before rolling
+    Variable(String),
+    Dice(Dice),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    /// An expression rolled several times, keeping the highest or lowest total, e.g. a(2d6+3)
+    Strategy(StrategyModifier, i32, Box<Node>),
+    /// A total held within bounds, e.g. (1d6-3)min0
+    Clamp(Clamp, Box<Node>),
+    /// A built-in function, e.g. max(1d6, 1d8)
+    Function(Function, Vec<Node>),
+    /// A term with a label, such as a damage type, e.g. 2d6[fire]
+    Tagged(String, Box<Node>),
+    /// Several expressions whose totals are added, perhaps keeping only some, e.g. {4d6,4d6}kh1
+    Group(Vec<Node>, Option<KeepDrop>),
+    /// Groups of dice kept or dropped together as one pool, e.g. {2d6+1d8}kh2
+    Pooled(Vec<Dice>, KeepDrop),
+}
+
+impl Node {
+    fn binary(operator: Operator, lhs: Node, rhs: Node) -> Self {
+        Node::Binary(operator, Box::new(lhs), Box::new(rhs))
+    }
+
+    /// Collects the dice of a sum of plain dice, returning false for anything else.
+    fn collect_pooled(&self, pooled: &mut Vec<Dice>) -> bool {
+        match self {
+            Node::Dice(dice) if dice.keep_drop.is_none() && dice.pool.is_none() => {
+                pooled.push(dice.clone());
+                true
+            }
+            Node::Binary(Operator::Add, lhs, rhs) => {
+                lhs.collect_pooled(pooled) && rhs.collect_pooled(pooled)
+            }
+            _ => false,
+        }
+    }
+
+    fn resolve(&self, profile: &Profile) -> Result<Node> {
+        Ok(match self {
+            Node::Constant(n) => Node::Constant(*n),
+            Node::Variable(name) => match profile.variables.get(name) {
+                Some(&value) => Node::Constant(value),
+                None => return Err(ExpressionError::UnknownVariable(name.clone())),
+            },
+            Node::Dice(dice) => Node::Dice(dice.resolve(profile)?),
+            Node::Negate(node) => Node::Negate(Box::new(node.resolve(profile)?)),
+            Node::Binary(operator, lhs, rhs) => {
+                let resolved = rhs.resolve(profile)?;
+                if matches!(operator, Operator::Divide(_)) && resolved.bounds().contains(&0) {
+                    let mut names = Vec::new();
+                    rhs.collect_variables(&mut names);
+                    return Err(ExpressionError::Unsupported(
+                        format!("@{}", names.join(", @")),
+                        "divisor may be zero",
+                    ));
+                }
+                Node::binary(*operator, lhs.resolve(profile)?, resolved)
+            }
+            Node::Strategy(strategy, rolls, node) => {
+                Node::Strategy(*strategy, *rolls, Box::new(node.resolve(profile)?))
+            }
+            Node::Clamp(clamp, node) => Node::Clamp(*clamp, Box::new(node.resolve(profile)?)),
+            Node::Function(function, args) => Node::Function(
+                *function,
+                args.iter()
+                    .map(|arg| arg.resolve(profile))
+                    .collect::<Result<_>>()?,
+            ),
+            Node::Tagged(label, node) => {
+                Node::Tagged(label.clone(), Box::new(node.resolve(profile)?))
+            }
+            Node::Group(members, keep_drop) => Node::Group(
+                members
+                    .iter()
+                    .map(|member| member.resolve(profile))
+                    .collect::<Result<_>>()?,
+                *keep_drop,
+            ),
+            Node::Pooled(dice, keep_drop) => Node::Pooled(
+                dice.iter()
+                    .map(|dice| dice.resolve(profile))
+                    .collect::<Result<_>>()?,
+                *keep_drop,
+            ),
+        })
+    }
+
+    /// Whether the subtree refers to a variable, whose value isn't known until it's resolved.
+    fn has_variables(&self) -> bool {
+        let mut names = Vec::new();
+        self.collect_variables(&mut names);
+        !names.is_empty()
+    }
+
+    /// Adds the name of each variable in the subtree, e.g. str for 1d8/@str.
+    fn collect_variables<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Node::Constant(_) | Node::Dice(_) | Node::Pooled(..) => {}
+            Node::Variable(name) => names.push(name),
+            Node::Negate(node)
+            | Node::Strategy(_, _, node)
+            | Node::Clamp(_, node)
+            | Node::Tagged(_, node) => node.collect_variables(names),
+            Node::Binary(_, lhs, rhs) => {
+                lhs.collect_variables(names);
+                rhs.collect_variables(names);
+            }
+            Node::Function(_, args) | Node::Group(args, _) => {
+                args.iter().for_each(|arg| arg.collect_variables(names));
+            }
+        }
+    }
+
+    /// Number of dice rolled, not counting rerolls and explosions.
+    fn dice_count(&self) -> i64 {
+        match self {
+            Node::Constant(_) | Node::Variable(_) => 0,
+            Node::Dice(dice) => dice.count as i64,
+            Node::Negate(node) => node.dice_count(),
+            Node::Binary(_, lhs, rhs) => lhs.dice_count().saturating_add(rhs.dice_count()),
+            Node::Strategy(_, rolls, node) => node.dice_count().saturating_mul(*rolls as i64),
+            Node::Clamp(_, node) | Node::Tagged(_, node) => node.dice_count(),
+            Node::Function(_, args) | Node::Group(args, _) => args
+                .iter()
+                .fold(0, |count, arg| count.saturating_add(arg.dice_count())),
+            Node::Pooled(dice, _) => dice
+                .iter()
+                .fold(0, |count, dice| count.saturating_add(dice.count as i64)),
+        }
+    }
+
+    /// Expected value, by linearity of expectation wherever possible.
+    ///
+    /// Subtrees roll their own dice, so they are independent and E[XY] = E[X]E[Y]. Rounded
+    /// division, the best or worst of several totals, clamped totals and functions are not
+    /// linear and are computed exactly from the full distribution instead. Kept totals and
+    /// dice use order statistics.
+    fn average_result(&self) -> f64 {
+        match self {
+            Node::Constant(n) => *n as f64,
+            Node::Variable(name) => unreachable!("variable @{name} must be resolved first"),
+            Node::Dice(dice) => dice.average_result(),
+            Node::Negate(node) => -node.average_result(),
+            Node::Tagged(_, node) => node.average_result(),
+            Node::Binary(operator, lhs, rhs) => match operator {
+                Operator::Add => lhs.average_result() + rhs.average_result(),
+                Operator::Subtract => lhs.average_result() - rhs.average_result(),
+                Operator::Multiply => lhs.average_result() * rhs.average_result(),
+                Operator::Divide(_) => self.distribution().mean(),
+            },
+            Node::Strategy(..) | Node::Clamp(..) | Node::Function(..) => self.distribution().mean(),
+            Node::Group(members, None) => members.iter().map(Node::average_result).sum(),
+            Node::Group(members, Some(keep_drop)) => {
+                let members: Vec<_> = members.iter().map(Node::distribution).collect();
+                distribution::order_statistic_sum(&members, keep_drop.kept(members.len()))
+            }
+            Node::Pooled(dice, keep_drop) => {
+                let dice: Vec<_> = dice.iter().flat_map(Dice::each_die).collect();
+                distribution::order_statistic_sum(&dice, keep_drop.kept(dice.len()))
+            }
+        }
+    }
+
+    fn distribution(&self) -> Distribution {
+        match self {
+            Node::Constant(n) => Distribution::constant(*n as i64),
+            Node::Variable(name) => unreachable!("variable @{name} must be resolved first"),
+            Node::Dice(dice) => dice.distribution(),
+            Node::Negate(node) => node.distribution().map(|x| -x),
+            Node::Tagged(_, node) => node.distribution(),
+            Node::Binary(operator, lhs, rhs) => lhs
+                .distribution()
+                .combine(&rhs.distribution(), |a, b| operator.apply(a, b)),
+            Node::Strategy(strategy, rolls, node) => match strategy {
+                StrategyModifier::Advantage => node.distribution().highest_of(*rolls),
+                StrategyModifier::Disadvantage => node.distribution().lowest_of(*rolls),
+                StrategyModifier::Normal => node.distribution(),
+            },
+            Node::Clamp(clamp, node) => node.distribution().map(|x| clamp.apply(x)),
+            Node::Function(function, args) => {
+                let mut args = args.iter().map(Node::distribution);
+                let first = args.next().unwrap_or_default();
+                match function {
+                    Function::Max => args.fold(first, |acc, d| acc.combine(&d, cmp::max)),
+                    Function::Min => args.fold(first, |acc, d| acc.combine(&d, cmp::min)),
+                    Function::Abs => first.map(i64::saturating_abs),
+                }
+            }
+            Node::Group(members, keep_drop) => {
+                let members: Vec<_> = members.iter().map(|m| (m.distribution(), 1)).collect();
+                let kept = match keep_drop {
+                    Some(keep_drop) => keep_drop.kept(members.len()),
+                    None => 0..members.len(),
+                };
+                distribution::kept_sum(&members, kept)
+            }
+            Node::Pooled(dice, keep_drop) => {
+                let classes: Vec<_> = dice.iter().flat_map(Dice::classes).collect();
+                let count = classes.iter().map(|(_, count)| count).sum();
+                distribution::kept_sum(&classes, keep_drop.kept(count))
+            }
+        }
+    }
+
+    /// The smallest and largest possible values, saturating for unbounded (exploding) dice.
+    fn bounds(&self) -> RangeInclusive<i64> {
+        match self {
+            Node::Constant(n) => *n as i64..=*n as i64,
+            // A variable could be set to anything by the time it's rolled.
+            Node::Variable(_) => i32::MIN as i64..=i32::MAX as i64,
+            Node::Dice(dice) => dice.bounds(),
+            Node::Negate(node) => {
+                let bounds = node.bounds();
+                bounds.end().saturating_neg()..=bounds.start().saturating_neg()
+            }
+            Node::Binary(operator, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.bounds(), rhs.bounds());
+                let corners = [
+                    operator.apply(*lhs.start(), *rhs.start()),
+                    operator.apply(*lhs.start(), *rhs.end()),
+                    operator.apply(*lhs.end(), *rhs.start()),
+                    operator.apply(*lhs.end(), *rhs.end()),
+                ];
+                let min = corners.iter().copied().min().unwrap_or_default();
+                let max = corners.iter().copied().max().unwrap_or_default();
+                min..=max
+            }
+            Node::Strategy(_, _, node) | Node::Tagged(_, node) => node.bounds(),
+            Node::Clamp(clamp, node) => {
+                let bounds = node.bounds();
+                clamp.apply(*bounds.start())..=clamp.apply(*bounds.end())
+            }
+            Node::Function(function, args) => {
+                let mut args = args.iter().map(Node::bounds);
+                let first = args.next().unwrap_or(0..=0);
+                let (start, end) = (*first.start(), *first.end());
+                match function {
+                    Function::Max => args.fold(first, |acc, b| {
+                        cmp::max(*acc.start(), *b.start())..=cmp::max(*acc.end(), *b.end())
+                    }),
+                    Function::Min => args.fold(first, |acc, b| {
+                        cmp::min(*acc.start(), *b.start())..=cmp::min(*acc.end(), *b.end())
+                    }),
+                    Function::Abs if start >= 0 => first,
+                    Function::Abs if end <= 0 => end.saturating_neg()..=start.saturating_neg(),
+                    Function::Abs => 0..=cmp::max(start.saturating_neg(), end),
+                }
+            }
+            Node::Group(members, keep_drop) => {
+                let bounds: Vec<_> = members.iter().map(Node::bounds).collect();
+                let kept = match keep_drop {
+                    Some(keep_drop) => keep_drop.kept(bounds.len()),
+                    None => 0..bounds.len(),
+                };
+                kept_bounds(bounds, kept)
+            }
+            Node::Pooled(dice, keep_drop) => {
+                let bounds: Vec<_> = dice
+                    .iter()
+                    .flat_map(|dice| {
+                        let single = Dice {
+                            count: 1,
+                            ..dice.clone()
+                        };
+                        iter::repeat_n(single.bounds(), dice.count.max(0) as usize)
+                    })
+                    .collect();
+                let kept = keep_drop.kept(bounds.len());
+                kept_bounds(bounds, kept)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide(Rounding),
+}
+
+impl Operator {
+    /// Applies the operator, saturating on overflow.
+    fn apply(self, lhs: i64, rhs: i64) -> i64 {
+        match self {
+            Operator::Add => lhs.saturating_add(rhs),
+            Operator::Subtract => lhs.saturating_sub(rhs),
+            Operator::Multiply => lhs.saturating_mul(rhs),
+            Operator::Divide(rounding) => rounding.divide(lhs, rhs).unwrap_or(i64::MAX),
+        }
+    }
+
+    fn checked_apply(self, lhs: i64, rhs: i64) -> Option<i64> {
+        match self {
+            Operator::Add => lhs.checked_add(rhs),
+            Operator::Subtract => lhs.checked_sub(rhs),
+            Operator::Multiply => lhs.checked_mul(rhs),
+            Operator::Divide(rounding) => rounding.divide(lhs, rhs),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Function {
+    Max,
+    Min,
+    Abs,
+}
+
+impl Function {
+    /// Applies the function, or returns None if the result overflows.
+    fn apply(self, args: &[i64]) -> Option<i64> {
+        match self {
+            Function::Max => args.iter().copied().max(),
+            Function::Min => args.iter().copied().min(),
+            Function::Abs => args.first()?.checked_abs(),
+        }
+    }
+
+    /// Index of the argument whose value is the result, if there is one.
+    fn chosen(self, args: &[i64]) -> Option<usize> {
+        match self {
+            Function::Max => Some(StrategyModifier::Advantage.choose(args)),
+            Function::Min => Some(StrategyModifier::Disadvantage.choose(args)),
+            Function::Abs => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+impl Rounding {
+    fn divide(self, lhs: i64, rhs: i64) -> Option<i64> {
+        let quotient = lhs.checked_div(rhs)?;
+        let is_inexact = lhs.checked_rem(rhs)? != 0;
+        match self {
+            Rounding::Down if is_inexact && (lhs < 0) != (rhs < 0) => quotient.checked_sub(1),
+            Rounding::Up if is_inexact && (lhs < 0) == (rhs < 0) => quotient.checked_add(1),
+            _ => Some(quotient),
+        }
+    }
+}
+
+/// A single group of identical dice, e.g. the 2d6 in 2d6+1d4+3.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
+pub struct Dice {
+    count: i32,
+    die: Die,
+    advantage: StrategyModifier,
+    /// Rolls of the first die beyond the usual two for advantage (aad20 is the best of three)
+    #[serde(default)]
+    extra_rolls: i32,
+    reroll: Option<Reroll>,
+    explode: Option<Explode>,
+    keep_drop: Option<KeepDrop>,
+    pool: Option<Pool>,
+    clamp: Option<Clamp>,
+    /// Values that count as a critical, e.g. c19 (by default, the highest face)
+    critical: Option<Comparison>,
+    /// Values that count as a fumble, e.g. cf2 (by default, the lowest face)
+    fumble: Option<Comparison>,
+    /// The game whose rules say how the roll turned out, e.g. for 7wod
+    #[serde(default)]
+    system: Option<System>,
+    /// Taken from the profile when the expression is resolved
+    #[serde(skip)]
+    limits: Limits,
+}
+
+impl Dice {
+    fn resolve(&self, profile: &Profile) -> Result<Dice> {
+        if let Die::Standard(max) = self.die
+            && max as i64 > profile.limits.max_faces
+        {
+            return Err(ExpressionError::TooManyFaces(
+                max as i64,
+                profile.limits.max_faces,
+            ));
+        }
+
+        let dice = match &self.die {
+            Die::Named(name) => {
+                let faces = profile
+                    .dice
+                    .get(name)
+                    .ok_or_else(|| ExpressionError::UnknownDie(name.clone()))?;
+                let dice = Dice {
+                    die: Die::Custom(faces.clone()),
+                    limits: profile.limits,
+                    ..self.clone()
+                };
+
+                // The thresholds of a named die can only be checked once its faces are known.
+                if let Some(problem) = dice.validate() {
+                    let text = format!("d{{{name}}}");
+                    return Err(ExpressionError::Unsupported(text, problem));
+                }
+                dice
+            }
+            _ => Dice {
+                limits: profile.limits,
+                ..self.clone()
+            },
+        };
+
+        if dice.could_overflow() {
+            return Err(ExpressionError::Overflow);
+        }
+        Ok(dice)
+    }
+
+    /// Whether a compounding die could explode to a total too large to hold, given how many
+    /// times it may explode.
+    fn could_overflow(&self) -> bool {
+        let Some(Explode::Compound(_)) = self.explode else {
+            return false;
+        };
+        let largest = cmp::max(self.die.min().unsigned_abs(), self.die.max().unsigned_abs());
+        let rolls = i64::try_from(self.limits.max_explosions).map_or(i64::MAX, |n| n + 1);
+        (largest as i64).saturating_mul(rolls) > i32::MAX as i64
+    }
+
+    /// The chance of each outcome under the rules of its game, for games with fixed outcomes.
+    fn outcome_chances(&self) -> Option<Vec<(Outcome, f64)>> {
+        let chances = match self.system? {
+            System::Blades => {
+                // The highest of n d6s: a critical needs two 6s, a full success exactly one.
+                let n = self.count;
+                let no_sixes = (5.0f64 / 6.0).powi(n);
+                let one_six = n as f64 / 6.0 * (5.0f64 / 6.0).powi(n - 1);
+                let all_low = 0.5f64.powi(n);
+                [
+                    (1.0 - no_sixes - one_six).max(0.0),
+                    one_six,
+                    no_sixes - all_low,
+                    all_low,
+                ]
+            }
+            // The lower of two d6s.
+            System::BladesZero => [0.0, 1.0 / 36.0, 8.0 / 36.0, 27.0 / 36.0],
+            System::Wod | System::Chance | System::Shadowrun | System::SavageWorlds => {
+                return None;
+            }
+        };
+        let outcomes = [
+            Outcome::Critical,
+            Outcome::FullSuccess,
+            Outcome::PartialSuccess,
+            Outcome::Bad,
+        ];
+        Some(outcomes.into_iter().zip(chances).collect())
+    }
+
+    /// Checks for dice without faces, rerolls and explosions that could never stop, and
+    /// critical or fumble ranges that hold on every face or none.
+    fn validate(&self) -> Option<&'static str> {
+        if let Die::Standard(max) = self.die
+            && max < 1
+        {
+            return Some("a die needs at least one face");
+        }
+        if let Some(problem) = self.range_problem() {
+            return Some(problem);
+        }
+
+        let faces = self.boundary_faces();
+        let is_once = self.reroll.is_some_and(|reroll| reroll.once);
+        if !is_once && faces.iter().all(|&k| self.reroll(k)) {
+            return Some("every face would be rerolled");
+        }
+
+        let mut settled = faces.iter().filter(|&&k| is_once || !self.reroll(k));
+        if self.explode.is_some() && settled.all(|&k| self.explode(k)) {
+            return Some("every face would explode");
+        }
+
+        None
+    }
+
+    /// Checks that each critical or fumble range given holds on some faces, but not all.
+    fn range_problem(&self) -> Option<&'static str> {
+        let faces = self.boundary_faces();
+        let ranges = [
+            (
+                self.critical,
+                "every face would be a critical",
+                "no face could be a critical",
+            ),
+            (
+                self.fumble,
+                "every face would be a fumble",
+                "no face could be a fumble",
+            ),
+        ];
+        for (range, every, none) in ranges {
+            let Some(range) = range else {
+                continue;
+            };
+            if faces.iter().all(|&k| range.matches(k)) {
+                return Some(every);
+            }
+            if !faces.iter().any(|&k| range.matches(k)) {
+                return Some(none);
+            }
+        }
+        None
+    }
+
+    /// Enough faces to tell whether a reroll or explode condition holds on every face.
+    ///
+    /// A condition holds on a contiguous run of values, so on a die numbered min..=max a run can
+    /// only start or end beside one of its thresholds. This keeps huge dice from being listed.
+    fn boundary_faces(&self) -> Vec<i32> {
+        if let Die::Custom(_) | Die::Named(_) = self.die {
+            return self.die.faces();
+        }
+
+        let (min, max) = (self.die.min(), self.die.max());
+        let conditions = self.reroll.map(|reroll| reroll.condition).into_iter();
+        let conditions = conditions.chain(self.explode.map(Explode::condition));
+        let conditions = conditions.chain(self.pool.map(|pool| pool.success));
+        let conditions = conditions.chain(self.pool.and_then(|pool| pool.failure));
+        let conditions = conditions.chain(self.critical).chain(self.fumble);
+        let thresholds = conditions.map(Comparison::threshold);
+        let bounds = self.clamp.iter().flat_map(|clamp| [clamp.min, clamp.max]);
+        let thresholds = thresholds.chain(bounds.flatten());
+
+        let mut faces = vec![min, max];
+        for n in thresholds {
+            faces.extend([n.saturating_sub(1), n, n.saturating_add(1)]);
+        }
+        faces.retain(|k| (min..=max).contains(k));
+        faces.sort_unstable();
+        faces.dedup();
+        faces
+    }
+
+    /// Splits the faces into runs on which rerolling, exploding and scoring don't change, except
+    /// that a clamped score follows the face between its bounds.
+    ///
+    /// Each face of a custom die is a run of its own.
+    fn runs(&self) -> Vec<RangeInclusive<i32>> {
+        let faces = self.boundary_faces();
+        if let Die::Custom(_) | Die::Named(_) = self.die {
+            return faces.into_iter().map(|k| k..=k).collect();
+        }
+
+        let mut runs = Vec::new();
+        let mut last: Option<i32> = None;
+        for k in faces {
+            if let Some(last) = last
+                && k > last + 1
+            {
+                runs.push(last + 1..=k - 1);
+            }
+            runs.push(k..=k);
+            last = Some(k);
+        }
+        runs
+    }
+
+    fn critical(&self) -> Comparison {
+        self.critical
+            .unwrap_or(Comparison::GreaterOrEqual(self.die.max()))
+    }
+
+    fn fumble(&self) -> Comparison {
+        self.fumble
+            .unwrap_or(Comparison::LessOrEqual(self.die.min()))
+    }
+
+    /// How many times the first die is rolled for advantage or disadvantage.
+    fn strategy_rolls(&self) -> i32 {
+        match self.advantage {
+            StrategyModifier::Normal => 1,
+            _ => 2 + self.extra_rolls,
+        }
+    }
+
+    fn reroll(&self, value: i32) -> bool {
+        self.reroll
+            .map(|x| x.should_reroll(value))
+            .unwrap_or_default()
+    }
+
+    fn explode(&self, value: i32) -> bool {
+        self.limits.max_explosions > 0
+            && self
+                .explode
+                .map(|x| x.should_explode(value))
+                .unwrap_or_default()
+    }
+
+    /// Expected value of a single die with reroll and explode, starting from a plain roll.
+    ///
+    /// Values ≤ r are rerolled, so every roll kept is uniform on [r+1, M]; values ≥ t explode
+    /// (add value and roll again). By Wald's identity, the chain totals the mean of one roll
+    /// times the expected number of rolls. Without a limit: E = (r+1+M)(M-r) / (2(t-1-r))
+    fn expected_plain(m: i64, r: i64, t: i64, explosions: usize) -> f64 {
+        (r + 1 + m) as f64 / 2.0 * Self::expected_rolls(m, r, t, explosions)
+    }
+
+    /// Expected number of rolls kept in a chain of explosions, starting from a plain roll.
+    fn expected_rolls(m: i64, r: i64, t: i64, explosions: usize) -> f64 {
+        let p = (m - t + 1).max(0) as f64 / (m - r) as f64;
+        Self::chain_length(p, explosions)
+    }
+
+    /// Expected number of rolls when each explodes with probability p, up to n times:
+    /// (1 - pⁿ⁺¹) / (1 - p)
+    fn chain_length(p: f64, explosions: usize) -> f64 {
+        if p > 0.0 {
+            (1.0 - p.powf(explosions as f64 + 1.0)) / (1.0 - p)
+        } else {
+            1.0
+        }
+    }
+
+    /// Expected value of the first die, which may have advantage or disadvantage.
+    ///
+    /// The initial roll uses the strategy (max/min of several rolls), then reroll and explode
+    /// apply normally. Rerolls and explode continuations are always single rolls.
+    fn expected_first_die(&self, r: i64, t: i64, e_plain: f64, e_continuation: f64) -> f64 {
+        match self.advantage {
+            StrategyModifier::Normal => e_plain,
+            _ => self
+                .first_roll()
+                .map(|(k, weight)| {
+                    let k = k as i64;
+                    let contribution = if k <= r {
+                        e_plain
+                    } else if k >= t {
+                        k as f64 + e_continuation
+                    } else {
+                        k as f64
+                    };
+                    weight * contribution
+                })
+                .sum(),
+        }
+    }
+
+    /// Value of a single roll: its face (within any clamp) for ordinary dice, or its score in
+    /// a pool.
+    fn score(&self, value: i32) -> i64 {
+        let value = self.clamp(value);
+        match self.pool {
+            Some(pool) => pool.score(value) as i64,
+            None => value as i64,
+        }
+    }
+
+    fn clamp(&self, value: i32) -> i32 {
+        match self.clamp {
+            Some(clamp) => clamp.apply(value as i64) as i32,
+            None => value,
+        }
+    }
+
+    /// Probability of each face on a single roll once any reroll is settled.
+    ///
+    /// Rerolling forever lands uniformly on the faces that don't match; rerolling once keeps
+    /// the second roll, whatever it shows.
+    fn roll_weights(&self) -> Vec<(i32, f64)> {
+        let faces = self.die.faces();
+        let n = faces.len() as f64;
+        let rerolled = faces.iter().filter(|&&k| self.reroll(k)).count() as f64;
+
+        faces
+            .into_iter()
+            .map(|k| {
+                let kept = if self.reroll(k) { 0.0 } else { 1.0 };
+                let weight = match self.reroll {
+                    None => 1.0 / n,
+                    Some(reroll) if reroll.once => (kept + rerolled / n) / n,
+                    Some(_) => kept / (n - rerolled),
+                };
+                (k, weight)
+            })
+            .collect()
+    }
+
+    /// Distribution of the rolls that follow an explosion.
+    ///
+    /// Each extra roll counts as its score, except that a compounding chain is left as a raw
+    /// total to be scored together with the roll that exploded.
+    fn continuation_distribution(&self) -> Distribution {
+        let weights = self.roll_weights();
+        let value = |k: i32| match self.explode {
+            Some(Explode::Compound(_)) => k as i64,
+            Some(explode) => self.score(explode.continuation(k)),
+            None => self.score(k),
+        };
+
+        let base = Distribution::from_weights(
+            weights
+                .iter()
+                .filter(|&&(k, _)| !self.explode(k))
+                .map(|&(k, weight)| (value(k), weight)),
+        );
+        let step = Distribution::from_weights(
+            weights
+                .iter()
+                .filter(|&&(k, _)| self.explode(k))
+                .map(|&(k, weight)| (value(k), weight)),
+        );
+
+        // The first roll of the die may already have used one of the explosions.
+        let limit = self.limits.max_explosions.saturating_sub(1);
+        Distribution::chain(&base, &step, limit)
+    }
+
+    /// Distribution of a die whose first roll (after any rerolls) shows `k`.
+    fn settle(&self, k: i32, continuation: &Distribution) -> Distribution {
+        match self.explode {
+            Some(Explode::Compound(_)) if self.explode(k) => {
+                // Compounded totals fit, as dice that could overflow are refused when resolved.
+                continuation.map(|total| {
+                    let total = (total + k as i64).clamp(i32::MIN as i64, i32::MAX as i64);
+                    self.score(total as i32)
+                })
+            }
+            Some(_) if self.explode(k) => {
+                continuation.convolve(&Distribution::constant(self.score(k)))
+            }
+            _ => Distribution::constant(self.score(k)),
+        }
+    }
+
+    /// Distribution of a single die without advantage, including rerolls and explosions.
+    fn plain_distribution(&self, continuation: &Distribution) -> Distribution {
+        let mut plain = Distribution::default();
+        for (k, weight) in self.roll_weights() {
+            plain.add_scaled(&self.settle(k, continuation), weight);
+        }
+        plain
+    }
+
+    /// Probability of each face on the first roll of the first die, which may have advantage
+    /// or disadvantage.
+    ///
+    /// For the face of rank i (from 1) out of m, the highest of n rolls lands on it with
+    /// probability (i/m)ⁿ - ((i-1)/m)ⁿ, and the lowest with ((m-i+1)/m)ⁿ - ((m-i)/m)ⁿ.
+    fn first_roll(&self) -> impl Iterator<Item = (i32, f64)> + '_ {
+        let faces = self.die.faces();
+        let m = faces.len() as f64;
+        let n = self.strategy_rolls();
+        let at_most = move |i: f64| (i / m).powi(n);
+        faces.into_iter().enumerate().map(move |(i, k)| {
+            let i = (i + 1) as f64;
+            let weight = match self.advantage {
+                StrategyModifier::Advantage => at_most(i) - at_most(i - 1.0),
+                StrategyModifier::Disadvantage => at_most(m - i + 1.0) - at_most(m - i),
+                StrategyModifier::Normal => 1.0 / m,
+            };
+            (k, weight)
+        })
+    }
+
+    /// Distribution of the first die, which may have advantage or disadvantage.
+    ///
+    /// A reroll of the first roll is a single roll: a plain die when rerolling forever, or a
+    /// roll that can't be rerolled again when rerolling once. Without advantage or disadvantage,
+    /// that makes the first die a plain one.
+    fn first_distribution(
+        &self,
+        plain: &Distribution,
+        continuation: &Distribution,
+    ) -> Distribution {
+        if self.advantage == StrategyModifier::Normal {
+            return plain.clone();
+        }
+
+        let mut first = Distribution::default();
+        let mut rerolled = 0.0;
+        for (k, weight) in self.first_roll() {
+            match self.reroll {
+                Some(reroll) if reroll.should_reroll(k) => rerolled += weight,
+                _ => first.add_scaled(&self.settle(k, continuation), weight),
+            }
+        }
+
+        match self.reroll {
+            Some(reroll) if reroll.once => {
+                let faces = self.die.faces();
+                let n = faces.len() as f64;
+                for k in faces {
+                    first.add_scaled(&self.settle(k, continuation), rerolled / n);
+                }
+            }
+            _ => first.add_scaled(plain, rerolled),
+        }
+        first
+    }
+
+    /// Distributions of the first die and of each die after it.
+    fn die_distributions(&self) -> (Distribution, Distribution) {
+        let continuation = self.continuation_distribution();
+        let plain = self.plain_distribution(&continuation);
+        let first = self.first_distribution(&plain, &continuation);
+        (first, plain)
+    }
+
+    /// Distributions of each die, which are identical after the first.
+    fn each_die(&self) -> Vec<Distribution> {
+        if self.count <= 0 {
+            return Vec::new();
+        }
+        let (first, plain) = self.die_distributions();
+        iter::once(first)
+            .chain(iter::repeat_n(plain, self.count as usize - 1))
+            .collect()
+    }
+
+    /// Distributions of the first die and of the rest, with how many dice have each.
+    fn classes(&self) -> Vec<(Distribution, usize)> {
+        if self.count <= 0 {
+            return Vec::new();
+        }
+        let (first, plain) = self.die_distributions();
+        vec![(first, 1), (plain, self.count as usize - 1)]
+    }
+
+    /// Distribution of the total (or net successes) of the whole group.
+    fn distribution(&self) -> Distribution {
+        if self.count <= 0 {
+            return Distribution::constant(0);
+        }
+
+        match self.keep_drop {
+            Some(keep_drop) => {
+                distribution::kept_sum(&self.classes(), keep_drop.kept(self.count as usize))
+            }
+            None => {
+                let (first, plain) = self.die_distributions();
+                let rest = self.count as usize - 1;
+                iter::repeat_n(&plain, rest).fold(first.clone(), |acc, d| acc.convolve(d))
+            }
+        }
+    }
+
+    fn bounds(&self) -> RangeInclusive<i64> {
+        let count = match self.keep_drop {
+            Some(keep_drop) => keep_drop.kept(self.count.max(0) as usize).len() as i64,
+            None => self.count.max(0) as i64,
+        };
+        // A score is the same across a run or follows the face, so it's lowest and highest at the
+        // ends of a run.
+        let is_once = self.reroll.is_some_and(|reroll| reroll.once);
+        let faces: Vec<_> = self
+            .runs()
+            .into_iter()
+            .flat_map(|run| [*run.start(), *run.end()])
+            .filter(|&k| is_once || !self.reroll(k))
+            .collect();
+        let faces = faces.iter().copied();
+        let lowest = faces
+            .clone()
+            .map(|k| self.score(k))
+            .min()
+            .unwrap_or_default();
+        let highest = match self.explode {
+            Some(_) => i64::MAX,
+            None => faces
+                .clone()
+                .map(|k| self.score(k))
+                .max()
+                .unwrap_or_default(),
+        };
+
+        // Extra rolls that can count for less than zero make the total unbounded below.
+        if let Some(explode) = self.explode
+            && faces
+                .map(|k| self.score(explode.continuation(k)))
+                .any(|score| score < 0)
+        {
+            return i64::MIN..=i64::MAX;
+        }
+        lowest.saturating_mul(count)..=highest.saturating_mul(count)
+    }
+
+    /// Thresholds (M, r, t) for the closed forms, which only hold when summing faces 1..=M
+    /// without clamps, rerolling values ≤ r forever and exploding on values ≥ t.
+    ///
+    /// They're widened so that a threshold beside the largest face doesn't overflow.
+    fn thresholds(&self) -> Option<(i64, i64, i64)> {
+        let (&Die::Standard(m), None, None) = (&self.die, self.pool, self.clamp) else {
+            return None;
+        };
+        let m = m as i64;
+        let r = match self.reroll {
+            Some(reroll) if !reroll.once => reroll.condition.at_most()?.max(0),
+            Some(_) => return None,
+            None => 0,
+        };
+        let t = match self.explode {
+            Some(_) if self.limits.max_explosions == 0 => m + 1,
+            Some(explode) => explode.condition().at_least()?.max(r + 1),
+            None => m + 1,
+        };
+        Some((m, r, t))
+    }
+
+    /// The chance that a single die that neither explodes nor is kept or dropped reaches
+    /// `value`, without listing its faces.
+    ///
+    /// Rolls kept are uniform on [r+1, M], so a plain roll reaches v with chance
+    /// (M - max(v, r+1) + 1) / (M - r). The first roll reaches v without a reroll when it's
+    /// above both v-1 and r, and at most x with chance (x/M)ⁿ for the highest of n rolls or
+    /// 1 - ((M-x)/M)ⁿ for the lowest.
+    fn chance_at_least(&self, value: i64) -> Option<f64> {
+        let (m, r, t) = self.thresholds()?;
+        if self.count != 1 || self.keep_drop.is_some() || t <= m {
+            return None;
+        }
+
+        let plain = (m - value.max(r + 1) + 1).clamp(0, m - r) as f64 / (m - r) as f64;
+        let n = self.strategy_rolls();
+        let at_most = |x: i64| {
+            let x = x.clamp(0, m) as f64 / m as f64;
+            match self.advantage {
+                StrategyModifier::Advantage => x.powi(n),
+                StrategyModifier::Disadvantage => 1.0 - (1.0 - x).powi(n),
+                StrategyModifier::Normal => x,
+            }
+        };
+        Some(1.0 - at_most((value - 1).max(r)) + at_most(r) * plain)
+    }
+
+    /// Whether every roll in a die's chain is a plain roll that scores on its own.
+    fn is_memoryless(&self) -> bool {
+        let is_once = self.reroll.is_some_and(|reroll| reroll.once);
+        !is_once && matches!(self.explode, None | Some(Explode::Standard(_)))
+    }
+
+    /// Expected score of a die when each roll in its chain scores separately, as in a pool.
+    ///
+    /// Continuations (rerolls and explosions) are plain rolls, so by Wald's identity a chain
+    /// scores the mean score of the K faces that aren't rerolled times the expected number of
+    /// rolls, where each roll explodes with probability X/K. Faces are counted a run at a time.
+    fn expected_score(&self) -> (f64, f64) {
+        let score = |k: i32| self.score(k) as f64;
+        let (mut kept, mut exploding, mut total) = (0.0, 0.0, 0.0);
+        for run in self
+            .runs()
+            .into_iter()
+            .filter(|run| !self.reroll(*run.start()))
+        {
+            let (lo, hi) = (*run.start(), *run.end());
+            let len = hi as f64 - lo as f64 + 1.0;
+            kept += len;
+            if self.explode(lo) {
+                exploding += len;
+            }
+            // The score is the same across the run or follows the face.
+            total += len * (score(lo) + score(hi)) / 2.0;
+        }
+        let p = exploding / kept;
+        let mean = total / kept;
+
+        let explosions = self.limits.max_explosions;
+        let e_plain = mean * Self::chain_length(p, explosions);
+        let e_continuation = mean * Self::chain_length(p, explosions.saturating_sub(1));
+
+        if self.advantage == StrategyModifier::Normal {
+            return (e_plain, e_plain);
+        }
+        let e_first = self
+            .first_roll()
+            .map(|(k, weight)| {
+                let contribution = if self.reroll(k) {
+                    e_plain
+                } else if self.explode(k) {
+                    score(k) + e_continuation
+                } else {
+                    score(k)
+                };
+                weight * contribution
+            })
+            .sum::<f64>();
+
+        (e_first, e_plain)
+    }
+
+    fn average_result(&self) -> f64 {
+        if self.count <= 0 {
+            return 0.0;
+        }
+
+        if let Some(keep_drop) = self.keep_drop {
+            let dice = self.each_die();
+            return distribution::order_statistic_sum(&dice, keep_drop.kept(dice.len()));
+        }
+
+        let (e_first, e_plain) = match self.thresholds() {
+            Some((m, r, t)) => {
+                // The rolls after an explosion have one fewer explosion left.
+                let explosions = self.limits.max_explosions;
+                let remaining = explosions.saturating_sub(1);
+                let mut e_plain = Self::expected_plain(m, r, t, explosions);
+                let mut e_continuation = Self::expected_plain(m, r, t, remaining);
+
+                // A penetrating chain loses one for every roll after the first.
+                if let Some(Explode::Penetrate(_)) = self.explode {
+                    e_plain -= Self::expected_rolls(m, r, t, explosions) - 1.0;
+                    e_continuation -= Self::expected_rolls(m, r, t, remaining);
+                }
+                let e_first = self.expected_first_die(r, t, e_plain, e_continuation);
+                (e_first, e_plain)
+            }
+            None if self.is_memoryless() => self.expected_score(),
+            // Compounded totals are scored as a whole, penetrating rolls are shifted, and a
+            // reroll-once depends on the roll before it, so the per-face method doesn't apply.
+            None => {
+                let (first, plain) = self.die_distributions();
+                (first.mean(), plain.mean())
+            }
+        };
+
+        e_first + (self.count - 1) as f64 * e_plain
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StrategyModifier {
+    Advantage,
+    Disadvantage,
+    #[default]
+    Normal,
+}
+
+impl StrategyModifier {
+    /// Index of the roll to keep: the first highest for advantage, the first lowest for
+    /// disadvantage.
+    fn choose<T: Ord>(self, rolls: &[T]) -> usize {
+        let rolls = rolls.iter().enumerate();
+        let chosen = match self {
+            StrategyModifier::Advantage => rolls.rev().max_by_key(|&(_, roll)| roll),
+            StrategyModifier::Disadvantage => rolls.min_by_key(|&(_, roll)| roll),
+            StrategyModifier::Normal => None,
+        };
+        chosen.map(|(idx, _)| idx).unwrap_or_default()
+    }
+}
+
+/// The faces of a die.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Die {
+    /// Faces 1 through N
+    Standard(i32),
+    /// Fate/Fudge dice with faces -1, 0 and +1
+    Fudge,
+    /// A d100 rolled as a tens die and a units die
+    Percentile,
+    /// An arbitrary face table, e.g. d{1,1,2,2,3,4}
+    Custom(Vec<Face>),
+    /// A face table stored in the profile, which must be resolved before rolling
+    Named(String),
+}
+
+impl Die {
+    /// Value of each face, in ascending order.
+    fn faces(&self) -> Vec<i32> {
+        match self {
+            Die::Standard(max) => (1..=*max).collect(),
+            Die::Fudge => vec![-1, 0, 1],
+            Die::Percentile => (1..=100).collect(),
+            Die::Custom(faces) => {
+                let mut values: Vec<_> = faces.iter().map(|face| face.value).collect();
+                values.sort_unstable();
+                values
+            }
+            Die::Named(_) => Vec::new(),
+        }
+    }
+
+    fn min(&self) -> i32 {
+        match self {
+            Die::Standard(_) | Die::Percentile => 1,
+            Die::Fudge => -1,
+            _ => self.faces().first().copied().unwrap_or_default(),
+        }
+    }
+
+    fn max(&self) -> i32 {
+        match self {
+            Die::Standard(max) => *max,
+            Die::Percentile => 100,
+            Die::Fudge => 1,
+            _ => self.faces().last().copied().unwrap_or_default(),
+        }
+    }
+
+    /// How a rolled value is shown in the output row, given the face of a custom die it was
+    /// rolled on, if it still shows that face's value.
+    fn label(&self, value: i32, face: Option<usize>) -> String {
+        match self {
+            Die::Standard(_) | Die::Named(_) => value.to_string(),
+            Die::Fudge => match value {
+                ..0 => String::from("[-]"),
+                0 => String::from("[ ]"),
+                _ => String::from("[+]"),
+            },
+            Die::Percentile => format!("{value} ({:02}+{})", value % 100 / 10 * 10, value % 10),
+            Die::Custom(faces) => match face.and_then(|face| faces[face].label.as_deref()) {
+                Some(label) => label.to_owned(),
+                None => value.to_string(),
+            },
+        }
+    }
+}
+
+impl Default for Die {
+    fn default() -> Self {
+        Die::Standard(0)
+    }
+}
+
+/// One face of a custom die, with an optional name such as "hit" or "crit".
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Face {
+    value: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+impl Face {
+    /// Parses a comma-separated face table, where each face is a number, a name worth zero, or
+    /// a name with a value (crit=2). Faces with different names may have the same value, as a
+    /// rolled face is told apart by its place in the table.
+    pub fn parse_table(text: &str) -> Result<Vec<Face>> {
+        text.split(',')
+            .map(|face| {
+                let face = face.trim();
+                if let Ok(value) = face.parse() {
+                    return Ok(Face { value, label: None });
+                }
+
+                let (label, value) = match face.split_once('=') {
+                    Some((label, value)) => (label.trim(), parse_integer(value.trim())?),
+                    None => (face, 0),
+                };
+                if label.is_empty() || !label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(ExpressionError::BadExpression(text.into()));
+                }
+
+                Ok(Face {
+                    value,
+                    label: Some(label.into()),
+                })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Face {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "{label}={}", self.value),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
+/// A game whose dice pools turn out better or worse than their totals alone say.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum System {
+    /// World of Darkness, where five successes are an exceptional success
+    Wod,
+    /// A World of Darkness chance die, where a 1 is a dramatic failure
+    Chance,
+    /// Shadowrun, where more than half the dice showing 1 is a glitch
+    Shadowrun,
+    /// Savage Worlds, where a 1 on both the trait and wild dice is a critical failure
+    SavageWorlds,
+    /// A Blades in the Dark action roll, where more than one 6 is a critical
+    Blades,
+    /// A Blades in the Dark roll with no dice, taking the lower of two, which can't be critical
+    BladesZero,
+}
+
+/// How a roll turned out under the rules of its game, beyond its total.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    ExceptionalSuccess,
+    DramaticFailure,
+    Glitch,
+    /// A glitch with no hits at all
+    CriticalGlitch,
+    /// Snake eyes, a 1 on both the trait die and the wild die
+    CriticalFailure,
+    /// A success by at least four, counting each four over the target
+    Raises(i64),
+    /// More than one 6 on an action roll
+    Critical,
+    FullSuccess,
+    PartialSuccess,
+    Bad,
+}
+
+impl Outcome {
+    pub fn highlight(self) -> Highlight {
+        match self {
+            Outcome::ExceptionalSuccess
+            | Outcome::Raises(_)
+            | Outcome::Critical
+            | Outcome::FullSuccess => Highlight::Success,
+            Outcome::PartialSuccess => Highlight::Normal,
+            Outcome::DramaticFailure
+            | Outcome::Glitch
+            | Outcome::CriticalGlitch
+            | Outcome::CriticalFailure
+            | Outcome::Bad => Highlight::Failure,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::ExceptionalSuccess => "exceptional success",
+            Outcome::DramaticFailure => "dramatic failure",
+            Outcome::Glitch => "glitch",
+            Outcome::CriticalGlitch => "critical glitch",
+            Outcome::CriticalFailure => "critical failure",
+            Outcome::Critical => "critical",
+            Outcome::FullSuccess => "full success",
+            Outcome::PartialSuccess => "partial success",
+            Outcome::Bad => "bad outcome",
+            Outcome::Raises(1) => "1 raise",
+            Outcome::Raises(n) => return write!(f, "{n} raises"),
+        })
+    }
+}
+
+/// Success and failure thresholds for counting successes instead of summing, e.g. 6d6>4f1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Pool {
+    success: Comparison,
+    failure: Option<Comparison>,
+}
+
+impl Pool {
+    /// A success counts for one; a failure subtracts one.
+    fn score(self, value: i32) -> i32 {
+        if self.success.matches(value) {
+            1
+        } else if self.failure.is_some_and(|failure| failure.matches(value)) {
+            -1
+        } else {
+            0
+        }
+    }
+}
+
+/// Bounds on a value, e.g. d20min10 counts any roll below 10 as 10.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Clamp {
+    min: Option<i32>,
+    max: Option<i32>,
+}
+
+impl Clamp {
+    fn apply(self, value: i64) -> i64 {
+        let value = self.min.map_or(value, |min| value.max(min as i64));
+        self.max.map_or(value, |max| value.min(max as i64))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Comparison {
+    Equal(i32),
+    Less(i32),
+    LessOrEqual(i32),
+    Greater(i32),
+    GreaterOrEqual(i32),
+}
+
+impl Comparison {
+    fn new(op: &str, value: i32) -> Self {
+        match op {
+            "=" => Comparison::Equal(value),
+            "<" => Comparison::Less(value),
+            "<=" => Comparison::LessOrEqual(value),
+            ">" => Comparison::Greater(value),
+            ">=" => Comparison::GreaterOrEqual(value),
+            _ => unreachable!("The tokenizer can't produce this"),
+        }
+    }
+
+    fn matches(self, value: i32) -> bool {
+        match self {
+            Comparison::Equal(n) => value == n,
+            Comparison::Less(n) => value < n,
+            Comparison::LessOrEqual(n) => value <= n,
+            Comparison::Greater(n) => value > n,
+            Comparison::GreaterOrEqual(n) => value >= n,
+        }
+    }
+
+    fn threshold(self) -> i32 {
+        match self {
+            Comparison::Equal(n)
+            | Comparison::Less(n)
+            | Comparison::LessOrEqual(n)
+            | Comparison::Greater(n)
+            | Comparison::GreaterOrEqual(n) => n,
+        }
+    }
+
+    /// The largest value matched, if the comparison matches everything up to some value.
+    fn at_most(self) -> Option<i64> {
+        match self {
+            Comparison::Less(n) => Some(n as i64 - 1),
+            Comparison::LessOrEqual(n) => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    /// The smallest value matched, if the comparison matches everything from some value up.
+    fn at_least(self) -> Option<i64> {
+        match self {
+            Comparison::Greater(n) => Some(n as i64 + 1),
+            Comparison::GreaterOrEqual(n) => Some(n as i64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum KeepDrop {
+    KeepHighest(i32),
+    KeepLowest(i32),
+    DropHighest(i32),
+    DropLowest(i32),
+}
+
+impl KeepDrop {
+    /// Positions of the dice that count toward the total when sorted in ascending order.
+    fn kept(self, count: usize) -> Range<usize> {
+        let clamp = |n: i32| cmp::min(n.max(0) as usize, count);
+        match self {
+            KeepDrop::KeepHighest(n) => count - clamp(n)..count,
+            KeepDrop::KeepLowest(n) => 0..clamp(n),
+            KeepDrop::DropHighest(n) => 0..count - clamp(n),
+            KeepDrop::DropLowest(n) => clamp(n)..count,
+        }
+    }
+}
+
+/// Rerolling values that match a condition, forever or only once.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Reroll {
+    condition: Comparison,
+    /// The second roll stands even if it matches again (2d6ro<3).
+    once: bool,
+}
+
+impl Reroll {
+    fn should_reroll(self, value: i32) -> bool {
+        self.condition.matches(value)
+    }
+}
+
+/// Rolling again on values that match a condition, and how the extra rolls count.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Explode {
+    /// Each extra roll is shown and added as its own result (2d6!).
+    Standard(Comparison),
+    /// Extra rolls fold into the value of the die that exploded (2d6!!).
+    Compound(Comparison),
+    /// Each extra roll counts one less, as in HackMaster (2d6!p).
+    Penetrate(Comparison),
+}
+
+impl Explode {
+    fn condition(self) -> Comparison {
+        match self {
+            Explode::Standard(condition)
+            | Explode::Compound(condition)
+            | Explode::Penetrate(condition) => condition,
+        }
+    }
+
+    fn should_explode(self, value: i32) -> bool {
+        self.condition().matches(value)
+    }
+
+    /// Value recorded for an extra roll.
+    fn continuation(self, value: i32) -> i32 {
+        match self {
+            Explode::Penetrate(_) => value.saturating_sub(1),
+            _ => value,
+        }
+    }
+}
+
+/// Limits that keep a roll from running away, configurable per profile.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Most dice in one expression, not counting rerolls and explosions
+    pub max_dice: i64,
+    /// Most faces on one die
+    pub max_faces: i64,
+    /// Most times a single die can explode; the last roll counts whatever it shows
+    pub max_explosions: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_dice: 10_000,
+            max_faces: 1_000_000,
+            max_explosions: 100,
+        }
+    }
+}
+
+pub trait Realizer {
+    fn next(&mut self, max: i32) -> i32;
+
+    /// Rolls a single die, whose faces need not be 1..=max.
+    fn roll(&mut self, die: &Die) -> i32 {
+        match die {
+            Die::Standard(max) => self.next(*max),
+            Die::Fudge => self.next(3) - 2,
+            Die::Percentile => {
+                // A tens die and a units die, each reading 0-9; 00 and 0 together read 100.
+                let tens = self.next(10) % 10;
+                let units = self.next(10) % 10;
+                match tens * 10 + units {
+                    0 => 100,
+                    value => value,
+                }
+            }
+            Die::Custom(faces) => faces[self.roll_face(faces)].value,
+            Die::Named(name) => unreachable!("die {name} must be resolved before rolling"),
+        }
+    }
+
+    /// Rolls a die with custom faces, returning the place in the table of the face rolled.
+    fn roll_face(&mut self, faces: &[Face]) -> usize {
+        self.next(faces.len() as i32) as usize - 1
+    }
+
+    /// Rolls a single die, along with which face came up on a die with custom faces.
+    fn roll_with_face(&mut self, die: &Die) -> (i32, Option<usize>) {
+        match die {
+            Die::Custom(faces) => {
+                let face = self.roll_face(faces);
+                (faces[face].value, Some(face))
+            }
+            _ => (self.roll(die), None),
+        }
+    }
+
+    /// Rolls an expression, failing if the total doesn't fit in 64 bits.
+    fn realize(&mut self, expression: &Expression) -> Result<RealizedExpression> {
+        let root = self.realize_node(&expression.root)?;
+        let sum = root.sum().ok_or(ExpressionError::Overflow)?;
+
+        let mut subtotals = Vec::new();
+        root.collect_subtotals(1, &mut subtotals)
+            .ok_or(ExpressionError::Overflow)?;
+        let untagged = subtotals
+            .iter()
+            .try_fold(sum, |rest, subtotal| rest.checked_sub(subtotal.value))
+            .ok_or(ExpressionError::Overflow)?;
+
+        Ok(RealizedExpression {
+            root,
+            sum,
+            subtotals,
+            untagged,
+            target: expression.target,
+            bands: expression.bands.clone(),
+        })
+    }
+
+    fn realize_node(&mut self, node: &Node) -> Result<RealizedNode> {
+        Ok(match node {
+            Node::Constant(n) => RealizedNode::Constant(*n),
+            Node::Variable(name) => {
+                unreachable!("variable @{name} must be resolved before rolling")
+            }
+            Node::Dice(dice) => RealizedNode::Dice(Box::new(self.realize_dice(dice)?)),
+            Node::Negate(node) => RealizedNode::Negate(Box::new(self.realize_node(node)?)),
+            Node::Binary(operator, lhs, rhs) => RealizedNode::Binary(
+                *operator,
+                Box::new(self.realize_node(lhs)?),
+                Box::new(self.realize_node(rhs)?),
+            ),
+            Node::Strategy(strategy, rolls, node) => {
+                let mut realized = Vec::new();
+                let mut totals = Vec::new();
+                for _ in 0..*rolls {
+                    let node = self.realize_node(node)?;
+                    totals.push(node.sum().ok_or(ExpressionError::Overflow)?);
+                    realized.push(node);
+                }
+
+                // Every roll but the one kept is shown as dropped.
+                let kept = strategy.choose(&totals);
+                for (idx, node) in realized.iter_mut().enumerate() {
+                    if idx != kept {
+                        node.drop_dice();
+                    }
+                }
+                RealizedNode::Strategy(realized, kept)
+            }
+            Node::Clamp(clamp, node) => {
+                RealizedNode::Clamp(*clamp, Box::new(self.realize_node(node)?))
+            }
+            Node::Function(function, args) => {
+                let mut realized = Vec::new();
+                let mut values = Vec::new();
+                for arg in args {
+                    let arg = self.realize_node(arg)?;
+                    values.push(arg.sum().ok_or(ExpressionError::Overflow)?);
+                    realized.push(arg);
+                }
+
+                // The arguments max() and min() passed over are shown as dropped.
+                if let Some(chosen) = function.chosen(&values) {
+                    for (idx, arg) in realized.iter_mut().enumerate() {
+                        if idx != chosen {
+                            arg.drop_dice();
+                        }
+                    }
+                }
+                RealizedNode::Function(*function, realized, values)
+            }
+            Node::Tagged(label, node) => {
+                RealizedNode::Tagged(label.clone(), Box::new(self.realize_node(node)?))
+            }
+            Node::Group(members, keep_drop) => {
+                let mut realized = Vec::new();
+                let mut totals = Vec::new();
+                for member in members {
+                    let member = self.realize_node(member)?;
+                    totals.push(member.sum().ok_or(ExpressionError::Overflow)?);
+                    realized.push(member);
+                }
+
+                // Members whose totals aren't kept are shown as dropped.
+                let mut is_kept = vec![true; realized.len()];
+                if let Some(keep_drop) = keep_drop {
+                    let mut order: Vec<usize> = (0..totals.len()).collect();
+                    order.sort_by_key(|&idx| totals[idx]);
+
+                    let kept = keep_drop.kept(order.len());
+                    for (position, idx) in order.into_iter().enumerate() {
+                        if !kept.contains(&position) {
+                            is_kept[idx] = false;
+                            realized[idx].drop_dice();
+                        }
+                    }
+                }
+                RealizedNode::Group(realized, totals, is_kept)
+            }
+            Node::Pooled(dice, keep_drop) => {
+                let mut realized = Vec::new();
+                for dice in dice {
+                    realized.push(self.realize_dice(dice)?);
+                }
+
+                // Every die is sorted together, whichever group it came from.
+                let mut order: Vec<(usize, usize)> = realized
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(group, dice)| (0..dice.results.len()).map(move |idx| (group, idx)))
+                    .collect();
+                order.sort_by_key(|&(group, idx)| {
+                    let dice = &realized[group];
+                    dice.results[idx].sum(dice.clamp)
+                });
+
+                let kept = keep_drop.kept(order.len());
+                for (position, (group, idx)) in order.into_iter().enumerate() {
+                    realized[group].results[idx].dropped = !kept.contains(&position);
+                }
+                RealizedNode::Pooled(realized)
+            }
+        })
+    }
+
+    fn realize_dice(&mut self, dice: &Dice) -> Result<RealizedDice> {
+        let mut results: SmallVec<[RolledDie; 4]> = SmallVec::new();
+        let mut advantage = Some(dice.advantage);
+
+        for _ in 0..dice.count {
+            let mut rolled = RolledDie::default();
+            let (mut value, mut face) = match advantage.take().unwrap_or_default() {
+                StrategyModifier::Normal => self.roll_with_face(&dice.die),
+                strategy => {
+                    let rolls: SmallVec<[(i32, Option<usize>); 4]> = (0..dice.strategy_rolls())
+                        .map(|_| self.roll_with_face(&dice.die))
+                        .collect();
+                    let values: SmallVec<[i32; 4]> = rolls.iter().map(|&(x, _)| x).collect();
+                    rolls[strategy.choose(&values)]
+                }
+            };
+
+            let mut is_rerolled = false;
+            let mut explosions = 0;
+            loop {
+                // If the value should be re-rolled, do not store it. A reroll-once keeps its
+                // second roll regardless.
+                if !is_rerolled && dice.reroll(value) {
+                    (value, face) = self.roll_with_face(&dice.die);
+                    is_rerolled = dice.reroll.is_some_and(|reroll| reroll.once);
+                    continue;
+                }
+
+                // Store value; a compounding explosion adds to the die that exploded, and a
+                // total or a shifted value no longer shows any one face.
+                match (dice.explode, rolled.rolls.last_mut()) {
+                    (None, _) | (_, None) => rolled.push(value, face),
+                    (Some(Explode::Compound(_)), Some(last)) => {
+                        *last = last.checked_add(value).ok_or(ExpressionError::Overflow)?;
+                        if let Some(face) = rolled.faces.last_mut() {
+                            *face = None;
+                        }
+                    }
+                    (Some(explode), Some(_)) => {
+                        let shifted = explode.continuation(value);
+                        rolled.push(shifted, face.filter(|_| shifted == value));
+                    }
+                }
+
+                // If the value should explode, roll another and continue (up to the limit).
+                if dice.explode(value) && explosions < dice.limits.max_explosions {
+                    (value, face) = self.roll_with_face(&dice.die);
+                    is_rerolled = false;
+                    explosions += 1;
+                    continue;
+                }
+
+                break;
+            }
+
+            results.push(rolled);
+        }
+
+        if let Some(keep_drop) = dice.keep_drop {
+            let mut order: SmallVec<[usize; 4]> = (0..results.len()).collect();
+            order.sort_by_key(|&idx| results[idx].sum(dice.clamp));
+
+            let kept = keep_drop.kept(order.len());
+            for (position, idx) in order.into_iter().enumerate() {
+                results[idx].dropped = !kept.contains(&position);
+            }
+        }
+
+        Ok(RealizedDice {
+            results,
+            die: dice.die.clone(),
+            pool: dice.pool,
+            clamp: dice.clamp,
+            critical: dice.critical(),
+            fumble: dice.fumble(),
+            has_ranges: dice.critical.is_some() || dice.fumble.is_some(),
+            system: dice.system,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RealizedExpression {
+    root: RealizedNode,
+    sum: i64,
+    /// The total of each label, in the order the labels first appear
+    subtotals: Vec<Subtotal>,
+    /// The part of the total that has no label
+    untagged: i64,
+    target: Option<i32>,
+    bands: Option<Bands>,
+}
+
+impl RealizedExpression {
+    pub fn sum(&self) -> i64 {
+        self.sum
+    }
+
+    /// Whether the total reached the target, if there is one.
+    pub fn check(&self) -> Option<Check> {
+        let target = self.target?;
+        Some(Check {
+            passed: self.sum >= target as i64,
+            margin: self.sum.saturating_sub(target as i64),
+        })
+    }
+
+    /// The band the total landed in, if the expression has bands.
+    pub fn band(&self) -> Option<(Highlight, &str)> {
+        Some(self.bands.as_ref()?.band(self.sum))
+    }
+
+    /// Starts a row with the total and the band it landed in. In a table with a band column,
+    /// a roll without bands leaves the band cell empty so that the columns after it line up.
+    pub fn sum_row(&self, band_column: bool) -> comfy_table::Row {
+        let mut row = comfy_table::Row::from([self.sum()]);
+        match self.band() {
+            Some((highlight, band)) => row.add_cell(highlight.cell(band)),
+            None if band_column => row.add_cell("".into()),
+            None => &mut row,
+        };
+        row
+    }
+
+    pub fn subtotals(&self) -> &[Subtotal] {
+        &self.subtotals
+    }
+
+    /// Halves the subtotals labelled as resisted, rounding down, and doubles those labelled as
+    /// vulnerable, e.g. for --resist fire. The sum is worked out again from the subtotals.
+    pub fn apply_resistances(&mut self, resist: &[String], vulnerable: &[String]) -> Result<()> {
+        let mut sum = self.untagged;
+        for subtotal in &mut self.subtotals {
+            subtotal.resisted = resist.contains(&subtotal.label);
+            subtotal.vulnerable = vulnerable.contains(&subtotal.label);
+            sum = subtotal
+                .adjusted()
+                .and_then(|value| sum.checked_add(value))
+                .ok_or(ExpressionError::Overflow)?;
+        }
+        self.sum = sum;
+        Ok(())
+    }
+
+    /// Each value rolled. A high roll on a subtracted die lowers the total, so its high and low
+    /// highlights are swapped.
+    pub fn results(&'_ self) -> impl Iterator<Item = (Highlight, i32)> + '_ {
+        let mut dice = Vec::new();
+        self.root.collect_dice(false, &mut dice);
+        dice.into_iter().flat_map(|(negated, dice)| {
+            dice.results()
+                .map(move |(highlight, x, _, _)| (highlight.negated_if(negated), x))
+        })
+    }
+
+    /// Results as they should be shown, e.g. [+] for a Fudge die, or 10 (3) for a roll of 3
+    /// raised to 10, and whether each die is subtracted from the total.
+    pub fn labelled_results(&'_ self) -> impl Iterator<Item = (Highlight, bool, String)> + '_ {
+        let mut dice = Vec::new();
+        self.root.collect_dice(false, &mut dice);
+        dice.into_iter().flat_map(|(negated, dice)| {
+            dice.results().map(move |(highlight, x, original, face)| {
+                let label = if x == original {
+                    dice.die.label(x, face)
+                } else {
+                    let (x, original) = (dice.die.label(x, None), dice.die.label(original, face));
+                    format!("{x} ({original})")
+                };
+                (highlight.negated_if(negated), negated, label)
+            })
+        })
+    }
+
+    /// The member totals of each brace group, and whether each was kept.
+    pub fn group_totals(&self) -> Vec<Vec<(i64, bool)>> {
+        let mut groups = Vec::new();
+        self.root.collect_groups(&mut groups);
+        groups
+    }
+
+    /// Totals changed by a clamp, as (before, after).
+    pub fn clamped_totals(&self) -> Vec<(i64, i64)> {
+        let mut clamps = Vec::new();
+        self.root.collect_clamps(&mut clamps);
+        clamps
+    }
+
+    /// Number of times each named face was rolled (and kept).
+    pub fn tally(&self) -> BTreeMap<&str, usize> {
+        let mut dice = Vec::new();
+        self.root.collect_dice(false, &mut dice);
+
+        let mut tally = BTreeMap::new();
+        for (_, dice) in dice {
+            let Die::Custom(faces) = &dice.die else {
+                continue;
+            };
+            let kept = dice.results.iter().filter(|die| !die.dropped);
+            for face in kept.flat_map(|die| &die.faces).flatten() {
+                if let Some(label) = &faces[*face].label {
+                    *tally.entry(label.as_str()).or_default() += 1;
+                }
+            }
+        }
+        tally
+    }
+
+    /// How the first roll with a game's rules turned out, e.g. an exceptional success on 7wod.
+    pub fn outcome(&self) -> Option<Outcome> {
+        let mut dice = Vec::new();
+        self.root.collect_dice(false, &mut dice);
+
+        // A Savage Worlds trait roll fails critically on snake eyes, a 1 on both the trait and
+        // the wild die. Otherwise, each 4 over the target (4 unless given) is a raise.
+        let savage: Vec<_> = dice
+            .iter()
+            .filter(|(_, dice)| dice.system == Some(System::SavageWorlds))
+            .collect();
+        if !savage.is_empty() {
+            let first_rolls = savage.iter().flat_map(|(_, dice)| dice.results.first());
+            if first_rolls.clone().count() > 1
+                && first_rolls.clone().all(|die| die.rolls.first() == Some(&1))
+            {
+                return Some(Outcome::CriticalFailure);
+            }
+            let target = self.target.unwrap_or(4) as i64;
+            let raises = self.sum.saturating_sub(target).div_euclid(4);
+            return (raises > 0).then_some(Outcome::Raises(raises));
+        }
+
+        dice.into_iter().find_map(|(_, dice)| dice.outcome())
+    }
+
+    /// Whether the only die that counts rolled in its critical range, e.g. a 20 on d20+5.
+    pub fn is_critical(&self) -> bool {
+        self.single_roll()
+            .is_some_and(|(dice, value)| dice.critical.matches(value))
+    }
+
+    /// Whether the only die that counts rolled in its fumble range, e.g. a 1 on 2d20kh1.
+    pub fn is_fumble(&self) -> bool {
+        self.single_roll()
+            .is_some_and(|(dice, value)| dice.fumble.matches(value))
+    }
+
+    /// Whether the only die that counts was given a critical or fumble range, e.g. d20c19+5.
+    fn has_ranges(&self) -> bool {
+        self.single_roll().is_some_and(|(dice, _)| dice.has_ranges)
+    }
+
+    /// The roll of the only die that isn't dropped, unless it exploded or counts successes.
+    fn single_roll(&self) -> Option<(&RealizedDice, i32)> {
+        let mut dice = Vec::new();
+        self.root.collect_dice(false, &mut dice);
+
+        let mut kept = dice.into_iter().flat_map(|(_, dice)| {
+            let kept = dice.results.iter().filter(|die| !die.dropped);
+            kept.map(move |die| (dice, die))
+        });
+        match (kept.next(), kept.next()) {
+            (Some((dice, die)), None) if dice.pool.is_none() => match *die.rolls {
+                [value] => Some((dice, value)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The result of comparing a total with its target.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Check {
+    pub passed: bool,
+    /// How far the total is above the target (or below it, if negative)
+    pub margin: i64,
+}
+
+impl Check {
+    pub fn highlight(&self) -> Highlight {
+        if self.passed {
+            Highlight::Success
+        } else {
+            Highlight::Failure
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let outcome = if self.passed { "pass" } else { "fail" };
+        write!(f, "{outcome} ({:+})", self.margin)
+    }
+}
+
+/// The part of a total that comes from terms with the same label, e.g. 9 fire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subtotal {
+    pub label: String,
+    pub value: i64,
+    pub resisted: bool,
+    pub vulnerable: bool,
+}
+
+impl Subtotal {
+    /// The value after halving or doubling, or None if it overflows.
+    pub fn adjusted(&self) -> Option<i64> {
+        let mut value = self.value;
+        if self.resisted {
+            value = value.div_euclid(2);
+        }
+        if self.vulnerable {
+            value = value.checked_mul(2)?;
+        }
+        Some(value)
+    }
+}
+
+impl fmt::Display for Subtotal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.adjusted().unwrap_or(self.value);
+        write!(f, "{} {}", value.abs(), self.label)?;
+        match (self.resisted, self.vulnerable) {
+            (true, true) => write!(f, " (halved, doubled)"),
+            (true, false) => write!(f, " (halved)"),
+            (false, true) => write!(f, " (doubled)"),
+            (false, false) => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum RealizedNode {
+    Constant(i32),
+    Dice(Box<RealizedDice>),
+    Negate(Box<RealizedNode>),
+    Binary(Operator, Box<RealizedNode>, Box<RealizedNode>),
+    /// Every roll of an expression with advantage, and the index of the one kept
+    Strategy(Vec<RealizedNode>, usize),
+    Clamp(Clamp, Box<RealizedNode>),
+    /// A function's arguments, and their totals from before any were dropped
+    Function(Function, Vec<RealizedNode>, Vec<i64>),
+    Tagged(String, Box<RealizedNode>),
+    /// The members of a group, their totals, and whether each was kept
+    Group(Vec<RealizedNode>, Vec<i64>, Vec<bool>),
+    Pooled(Vec<RealizedDice>),
+}
+
+impl RealizedNode {
+    /// The total, or None if it overflows.
+    fn sum(&self) -> Option<i64> {
+        match self {
+            RealizedNode::Constant(n) => Some(*n as i64),
+            RealizedNode::Dice(dice) => Some(dice.sum()),
+            RealizedNode::Negate(node) => node.sum()?.checked_neg(),
+            RealizedNode::Binary(operator, lhs, rhs) => {
+                operator.checked_apply(lhs.sum()?, rhs.sum()?)
+            }
+            RealizedNode::Strategy(rolls, kept) => rolls[*kept].sum(),
+            RealizedNode::Clamp(clamp, node) => Some(clamp.apply(node.sum()?)),
+            RealizedNode::Function(function, _, values) => function.apply(values),
+            RealizedNode::Tagged(_, node) => node.sum(),
+            RealizedNode::Group(_, totals, is_kept) => totals
+                .iter()
+                .zip(is_kept)
+                .filter(|&(_, &is_kept)| is_kept)
+                .try_fold(0i64, |sum, (&total, _)| sum.checked_add(total)),
+            RealizedNode::Pooled(dice) => dice
+                .iter()
+                .try_fold(0i64, |sum, dice| sum.checked_add(dice.sum())),
+        }
+    }
+
+    /// Adds this node's total, times `sign`, to the subtotals of its labels. Sums and
+    /// differences are followed into their terms; any other node counts towards a label only
+    /// if it's the only one inside, so 2d6[fire]*2 is all fire. Returns None on overflow.
+    fn collect_subtotals(&self, sign: i64, subtotals: &mut Vec<Subtotal>) -> Option<()> {
+        let label = match self {
+            RealizedNode::Negate(node) => return node.collect_subtotals(-sign, subtotals),
+            RealizedNode::Binary(operator @ (Operator::Add | Operator::Subtract), lhs, rhs) => {
+                lhs.collect_subtotals(sign, subtotals)?;
+                let sign = match operator {
+                    Operator::Subtract => -sign,
+                    _ => sign,
+                };
+                return rhs.collect_subtotals(sign, subtotals);
+            }
+            RealizedNode::Tagged(label, _) => label,
+            _ => {
+                let mut labels = Vec::new();
+                self.collect_labels(&mut labels);
+                match &*labels {
+                    [label] => *label,
+                    _ => return Some(()),
+                }
+            }
+        };
+
+        let value = self.sum()?.checked_mul(sign)?;
+        match subtotals
+            .iter_mut()
+            .find(|subtotal| subtotal.label == *label)
+        {
+            Some(subtotal) => subtotal.value = subtotal.value.checked_add(value)?,
+            None => subtotals.push(Subtotal {
+                label: label.clone(),
+                value,
+                resisted: false,
+                vulnerable: false,
+            }),
+        }
+        Some(())
+    }
+
+    /// Collects the distinct labels of the outermost tagged nodes.
+    fn collect_labels<'a>(&'a self, labels: &mut Vec<&'a String>) {
+        match self {
+            RealizedNode::Constant(_) | RealizedNode::Dice(_) | RealizedNode::Pooled(_) => {}
+            RealizedNode::Tagged(label, _) => {
+                if !labels.contains(&label) {
+                    labels.push(label);
+                }
+            }
+            RealizedNode::Negate(node) | RealizedNode::Clamp(_, node) => {
+                node.collect_labels(labels)
+            }
+            RealizedNode::Binary(_, lhs, rhs) => {
+                lhs.collect_labels(labels);
+                rhs.collect_labels(labels);
+            }
+            RealizedNode::Strategy(nodes, _)
+            | RealizedNode::Function(_, nodes, _)
+            | RealizedNode::Group(nodes, ..) => {
+                for node in nodes {
+                    node.collect_labels(labels);
+                }
+            }
+        }
+    }
+
+    /// Collects each clamped total that differs from the total before clamping, as a pair of
+    /// (before, after).
+    fn collect_clamps(&self, clamps: &mut Vec<(i64, i64)>) {
+        match self {
+            RealizedNode::Constant(_) | RealizedNode::Dice(_) | RealizedNode::Pooled(_) => {}
+            RealizedNode::Group(members, _, is_kept) => {
+                for (member, _) in members.iter().zip(is_kept).filter(|&(_, &is_kept)| is_kept) {
+                    member.collect_clamps(clamps);
+                }
+            }
+            RealizedNode::Negate(node) | RealizedNode::Tagged(_, node) => {
+                node.collect_clamps(clamps)
+            }
+            RealizedNode::Binary(_, lhs, rhs) => {
+                lhs.collect_clamps(clamps);
+                rhs.collect_clamps(clamps);
+            }
+            RealizedNode::Strategy(rolls, kept) => rolls[*kept].collect_clamps(clamps),
+            RealizedNode::Function(function, args, values) => match function.chosen(values) {
+                Some(chosen) => args[chosen].collect_clamps(clamps),
+                None => {
+                    for arg in args {
+                        arg.collect_clamps(clamps);
+                    }
+                }
+            },
+            RealizedNode::Clamp(clamp, node) => {
+                node.collect_clamps(clamps);
+                if let Some(sum) = node.sum()
+                    && clamp.apply(sum) != sum
+                {
+                    clamps.push((sum, clamp.apply(sum)));
+                }
+            }
+        }
+    }
+
+    /// Collects the member totals of each brace group, and whether each was kept.
+    fn collect_groups(&self, groups: &mut Vec<Vec<(i64, bool)>>) {
+        match self {
+            RealizedNode::Constant(_) | RealizedNode::Dice(_) | RealizedNode::Pooled(_) => {}
+            RealizedNode::Negate(node)
+            | RealizedNode::Clamp(_, node)
+            | RealizedNode::Tagged(_, node) => node.collect_groups(groups),
+            RealizedNode::Binary(_, lhs, rhs) => {
+                lhs.collect_groups(groups);
+                rhs.collect_groups(groups);
+            }
+            RealizedNode::Strategy(nodes, _) | RealizedNode::Function(_, nodes, _) => {
+                for node in nodes {
+                    node.collect_groups(groups);
+                }
+            }
+            RealizedNode::Group(members, totals, is_kept) => {
+                groups.push(
+                    totals
+                        .iter()
+                        .copied()
+                        .zip(is_kept.iter().copied())
+                        .collect(),
+                );
+                for member in members {
+                    member.collect_groups(groups);
+                }
+            }
+        }
+    }
+
+    /// Collects every group of dice in the order they appear in the expression, and whether
+    /// each is subtracted, as in 1d20-1d4 or -(1d6+1d4).
+    fn collect_dice<'a>(&'a self, negated: bool, dice: &mut Vec<(bool, &'a RealizedDice)>) {
+        match self {
+            RealizedNode::Constant(_) => {}
+            RealizedNode::Dice(realized) => dice.push((negated, realized.as_ref())),
+            RealizedNode::Negate(node) => node.collect_dice(!negated, dice),
+            RealizedNode::Binary(operator, lhs, rhs) => {
+                lhs.collect_dice(negated, dice);
+                let negated = negated != matches!(operator, Operator::Subtract);
+                rhs.collect_dice(negated, dice);
+            }
+            RealizedNode::Strategy(rolls, _) => {
+                for node in rolls {
+                    node.collect_dice(negated, dice);
+                }
+            }
+            RealizedNode::Clamp(_, node) | RealizedNode::Tagged(_, node) => {
+                node.collect_dice(negated, dice)
+            }
+            RealizedNode::Function(_, args, _) | RealizedNode::Group(args, ..) => {
+                for arg in args {
+                    arg.collect_dice(negated, dice);
+                }
+            }
+            RealizedNode::Pooled(pooled) => {
+                dice.extend(pooled.iter().map(|realized| (negated, realized)))
+            }
+        }
+    }
+
+    /// Marks every die as dropped, e.g. in a roll that lost to one with advantage.
+    fn drop_dice(&mut self) {
+        match self {
+            RealizedNode::Constant(_) => {}
+            RealizedNode::Dice(realized) => {
+                for die in &mut realized.results {
+                    die.dropped = true;
+                }
+            }
+            RealizedNode::Negate(node) | RealizedNode::Tagged(_, node) => node.drop_dice(),
+            RealizedNode::Binary(_, lhs, rhs) => {
+                lhs.drop_dice();
+                rhs.drop_dice();
+            }
+            RealizedNode::Strategy(rolls, _) => {
+                for node in rolls {
+                    node.drop_dice();
+                }
+            }
+            RealizedNode::Clamp(_, node) => node.drop_dice(),
+            RealizedNode::Function(_, args, _) | RealizedNode::Group(args, ..) => {
+                for arg in args {
+                    arg.drop_dice();
+                }
+            }
+            RealizedNode::Pooled(pooled) => {
+                for die in pooled.iter_mut().flat_map(|dice| &mut dice.results) {
+                    die.dropped = true;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RealizedDice {
+    results: SmallVec<[RolledDie; 4]>,
+    die: Die,
+    pool: Option<Pool>,
+    clamp: Option<Clamp>,
+    critical: Comparison,
+    fumble: Comparison,
+    /// Whether a critical or fumble range was given, e.g. d20c19, so that rolls in either
+    /// range are called out
+    has_ranges: bool,
+    system: Option<System>,
+}
+
+impl RealizedDice {
+    /// How the roll turned out under the rules of its game, if it has one.
+    fn outcome(&self) -> Option<Outcome> {
+        match self.system? {
+            System::Wod => (self.sum() >= 5).then_some(Outcome::ExceptionalSuccess),
+            System::Chance => {
+                let first = self.results.first()?.rolls.first()?;
+                (*first == 1).then_some(Outcome::DramaticFailure)
+            }
+            System::Shadowrun => {
+                // Only the dice of the pool count towards a glitch, not those rolled for edge.
+                let ones = self
+                    .results
+                    .iter()
+                    .filter(|die| die.rolls.first() == Some(&1));
+                if ones.count() * 2 <= self.results.len() {
+                    None
+                } else if self.sum() == 0 {
+                    Some(Outcome::CriticalGlitch)
+                } else {
+                    Some(Outcome::Glitch)
+                }
+            }
+            // A trait roll turns on both of its dice, so it's judged by the whole expression.
+            System::SavageWorlds => None,
+            System::Blades | System::BladesZero => {
+                let sixes = self
+                    .results
+                    .iter()
+                    .filter(|die| die.rolls.first() == Some(&6));
+                if self.system == Some(System::Blades) && sixes.count() > 1 {
+                    return Some(Outcome::Critical);
+                }
+                Some(match self.sum() {
+                    6.. => Outcome::FullSuccess,
+                    4 | 5 => Outcome::PartialSuccess,
+                    _ => Outcome::Bad,
+                })
+            }
+        }
+    }
+
+    /// The total of the kept dice or, for a pool, the net number of successes.
+    fn sum(&self) -> i64 {
+        let kept = self.results.iter().filter(|die| !die.dropped);
+        match self.pool {
+            Some(pool) => kept
+                .flat_map(|die| &die.rolls)
+                .map(|&x| pool.score(self.clamp(x)) as i64)
+                .sum(),
+            None => kept.map(|die| die.sum(self.clamp)).sum(),
+        }
+    }
+
+    fn clamp(&self, value: i32) -> i32 {
+        match self.clamp {
+            Some(clamp) => clamp.apply(value as i64) as i32,
+            None => value,
+        }
+    }
+
+    /// Each value counted, with its highlight, the value rolled before any clamp and the face
+    /// of a custom die it shows.
+    ///
+    /// Low and high highlights mark fumbles and criticals. They go by the roll itself, so a 1
+    /// raised to 2 still shows as a 1.
+    fn results(&'_ self) -> impl Iterator<Item = (Highlight, i32, i32, Option<usize>)> + '_ {
+        self.results.iter().flat_map(move |die| {
+            let rolls = die.rolls.iter().zip(&die.faces);
+            rolls.map(move |(&original, &face)| {
+                let x = self.clamp(original);
+                let highlight = if die.dropped {
+                    Highlight::Dropped
+                } else if let Some(pool) = self.pool {
+                    match pool.score(x) {
+                        1 => Highlight::Success,
+                        -1 => Highlight::Failure,
+                        _ => Highlight::Normal,
+                    }
+                } else if self.fumble.matches(original) {
+                    Highlight::Low
+                } else if self.critical.matches(original) {
+                    Highlight::High
+                } else {
+                    Highlight::Normal
+                };
+                (highlight, x, original, face)
+            })
+        })
+    }
+}
+
+/// A single die along with any explosions rolled for it.
+#[derive(Clone, Debug, Default)]
+struct RolledDie {
+    rolls: SmallVec<[i32; 2]>,
+    /// The place in the table of the face each roll of a custom die shows
+    faces: SmallVec<[Option<usize>; 2]>,
+    dropped: bool,
+}
+
+impl RolledDie {
+    fn push(&mut self, value: i32, face: Option<usize>) {
+        self.rolls.push(value);
+        self.faces.push(face);
+    }
+
+    /// The total of the rolls, each within the clamp if there is one.
+    fn sum(&self, clamp: Option<Clamp>) -> i64 {
+        let rolls = self.rolls.iter().map(|&x| x as i64);
+        match clamp {
+            Some(clamp) => rolls.map(|x| clamp.apply(x)).sum(),
+            None => rolls.sum(),
+        }
+    }
+}
+
+impl From<RealizedExpression> for comfy_table::Row {
+    fn from(value: RealizedExpression) -> Self {
+        value.into_row(None, false)
+    }
+}
+
+impl RealizedExpression {
+    /// The outcome of a game's roll (or else a critical or fumble mark, for a die given a range)
+    /// and the subtotal of each label, e.g. " (critical) = 9 fire + 3", to follow the dice or the
+    /// expression in a row.
+    pub fn notes(&self) -> String {
+        use std::fmt::Write;
+
+        let mut w = String::new();
+        if let Some(outcome) = self.outcome() {
+            write!(w, " ({})", outcome.highlight().paint(outcome)).unwrap();
+        } else if self.has_ranges() && self.is_critical() {
+            write!(w, " ({})", Highlight::High.paint("critical")).unwrap();
+        } else if self.has_ranges() && self.is_fumble() {
+            write!(w, " ({})", Highlight::Low.paint("fumble")).unwrap();
+        }
+
+        let mut subtotals = self.subtotals().iter();
+        if let Some(first) = subtotals.next() {
+            let sign = if first.value < 0 { "-" } else { "" };
+            write!(w, " = {sign}{first}").unwrap();
+            for subtotal in subtotals {
+                let sign = if subtotal.value < 0 { "-" } else { "+" };
+                write!(w, " {sign} {subtotal}").unwrap();
+            }
+            match self.untagged {
+                0 => {}
+                n if n < 0 => write!(w, " - {}", -n).unwrap(),
+                n => write!(w, " + {n}").unwrap(),
+            }
+        }
+        w
+    }
+
+    /// A row with the total, the band, the dice rolled and whether the target was reached.
+    ///
+    /// One of several rolls of the same expression is marked with its number (#2).
+    pub fn into_row(self, number: Option<u32>, band_column: bool) -> comfy_table::Row {
+        use std::fmt::Write;
+
+        let mut row = self.sum_row(band_column);
+
+        let mut results = self.labelled_results();
+        let number = number.map(|number| format!("#{number}"));
+        let mut w = String::new();
+
+        if let Some((highlight, negated, value)) = results.next() {
+            let number = number.as_deref().unwrap_or_default();
+            let sign = if negated { "-" } else { "" };
+            write!(w, "{number:>3} = {sign}{}", highlight.paint(value)).unwrap();
+        } else if let Some(number) = &number {
+            write!(w, "{number:>3}").unwrap();
+        }
+
+        for (highlight, negated, value) in results {
+            let sign = if negated { "-" } else { "+" };
+            write!(w, " {sign} {}", highlight.paint(value)).unwrap();
+        }
+
+        let tally: Vec<_> = self
+            .tally()
+            .into_iter()
+            .map(|(label, count)| format!("{count} {label}"))
+            .collect();
+        if !tally.is_empty() {
+            write!(w, " ({})", tally.join(", ")).unwrap();
+        }
+
+        for (before, after) in self.clamped_totals() {
+            write!(w, " ({before} clamped to {after})").unwrap();
+        }
+
+        for group in self.group_totals() {
+            let totals: Vec<_> = group
+                .into_iter()
+                .map(|(total, is_kept)| match is_kept {
+                    true => Highlight::Normal.paint(total),
+                    false => Highlight::Dropped.paint(total),
+                })
+                .collect();
+            write!(w, " {{{}}}", totals.join(", ")).unwrap();
+        }
+
+        w.push_str(&self.notes());
+
+        row.add_cell(w.into());
+        if let Some(check) = self.check() {
+            row.add_cell(check.highlight().paint(check).into());
+        }
+        row
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Highlight {
+    High,
+    Low,
+    Normal,
+    Dropped,
+    Success,
+    Failure,
+}
+
+impl Highlight {
+    /// Swaps high and low for a die subtracted from the total, when `negated` is true.
+    fn negated_if(self, negated: bool) -> Self {
+        match (self, negated) {
+            (Highlight::High, true) => Highlight::Low,
+            (Highlight::Low, true) => Highlight::High,
+            (highlight, _) => highlight,
+        }
+    }
+
+    /// Formats `value` in this highlight's colour.
+    pub fn paint(self, value: impl fmt::Display) -> String {
+        match self {
+            Highlight::High => value.bright_green().to_string(),
+            Highlight::Low => value.bright_red().to_string(),
+            Highlight::Normal => value.to_string(),
+            Highlight::Dropped => value.dimmed().strikethrough().to_string(),
+            Highlight::Success => value.green().bold().to_string(),
+            Highlight::Failure => value.red().bold().to_string(),
+        }
+    }
+
+    /// A cell showing `value` in this highlight's colour.
+    ///
+    /// The table styles the cell itself, so unlike a painted value, the colour doesn't count
+    /// towards the width of its column.
+    pub fn cell(self, value: impl fmt::Display) -> comfy_table::Cell {
+        use comfy_table::{Attribute, Cell, Color};
+
+        let cell = Cell::new(value);
+        match self {
+            Highlight::High => cell.fg(Color::Green),
+            Highlight::Low => cell.fg(Color::Red),
+            Highlight::Normal => cell,
+            Highlight::Dropped => cell.add_attributes(vec![Attribute::Dim, Attribute::CrossedOut]),
+            Highlight::Success => cell.fg(Color::DarkGreen).add_attribute(Attribute::Bold),
+            Highlight::Failure => cell.fg(Color::DarkRed).add_attribute(Attribute::Bold),
+        }
+    }
+}
+
+/// Bounds of the sum of the values in positions `kept` when sorted in ascending order.
+///
+/// The value in each position is at least the same position among the lowest values and at
+/// most the same position among the highest.
+fn kept_bounds(bounds: Vec<RangeInclusive<i64>>, kept: Range<usize>) -> RangeInclusive<i64> {
+    let mut lowest: Vec<_> = bounds.iter().map(|b| *b.start()).collect();
+    let mut highest: Vec<_> = bounds.iter().map(|b| *b.end()).collect();
+    lowest.sort_unstable();
+    highest.sort_unstable();
+    let sum = |values: &[i64]| values.iter().fold(0i64, |acc, &x| acc.saturating_add(x));
+    sum(&lowest[kept.clone()])..=sum(&highest[kept])
+}
+
+/// Whether `text` can name a die or variable: a letter, then letters, digits or underscores.
+pub fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic())
+        && text.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn parse_integer(text: &str) -> Result<i32> {
+    text.parse()
+        .map_err(|e| ExpressionError::BadInteger(text.into(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::ExpressionError, profile::Profile};
+
+    use crate::expression::{
+        Clamp, Comparison, Dice, Die, Explode, Expression, ExpressionParser, Face, Function,
+        Highlight, KeepDrop, Node, Operator, Outcome, Pool, Realizer, Reroll, Rounding,
+        StrategyModifier, System,
+    };
+
+    #[test]
+    fn bounded_expression() {
+        let expression = parse("2d6");
+        assert_eq!(count_max(2, 6), expression);
+    }
+
+    #[test]
+    fn leading_bounded_expression() {
+        let a = parse("20");
+        let b = parse("d20");
+        let expected = count_max(1, 20);
+        assert_eq!(a, expected);
+        assert_eq!(b, expected);
+        assert_eq!(parse("20r2 vs 10").root, parse("d20r2").root);
+    }
+
+    #[test]
+    fn leading_number_in_arithmetic() {
+        let binary = |operator, lhs, rhs: &str| {
+            Node::Binary(
+                operator,
+                Box::new(Node::Constant(lhs)),
+                Box::new(parse(rhs).root),
+            )
+        };
+        assert_eq!(parse("3+2d6").root, binary(Operator::Add, 3, "2d6"));
+        assert_eq!(parse("10-1d4").root, binary(Operator::Subtract, 10, "1d4"));
+        assert_eq!(parse("5*2d6").root, binary(Operator::Multiply, 5, "2d6"));
+
+        let mut realizer = MockRealizer::new(vec![1, 2]);
+        assert_eq!(6, realizer.realize(&parse("3+2d6")).unwrap().sum());
+
+        // Without other dice, it's still shorthand for a die.
+        let die_and = |operator, rhs| {
+            Node::Binary(
+                operator,
+                Box::new(Node::Dice(dice(1, 20))),
+                Box::new(Node::Constant(rhs)),
+            )
+        };
+        assert_eq!(parse("20+5").root, die_and(Operator::Add, 5));
+        assert_eq!(parse("20-1").root, die_and(Operator::Subtract, 1));
+        assert_eq!(parse("20+5 vs 15").root, die_and(Operator::Add, 5));
+        assert_close(avg("20+5"), 15.5);
+    }
+
+    #[test]
+    fn bounded_expression_with_reroll() {
+        let actual = parse("2d6r");
+        let expected = single(Dice {
+            count: 2,
+            die: Die::Standard(6),
+            reroll: Some(reroll(1)),
+            ..Default::default()
+        });
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn bounded_expression_with_reroll_2() {
+        let actual = parse("2d6r2");
+        let expected = single(Dice {
+            count: 2,
+            die: Die::Standard(6),
+            reroll: Some(reroll(2)),
+            ..Default::default()
+        });
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn bounded_expression_with_explode() {
+        let actual = parse("2d6!");
+        let expected = single(Dice {
+            count: 2,
+            die: Die::Standard(6),
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(6))),
+            ..Default::default()
+        });
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn bounded_expression_with_explode_5() {
+        let actual = parse("2d6!5");
+        let expected = single(Dice {
+            count: 2,
+            die: Die::Standard(6),
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(5))),
+            ..Default::default()
+        });
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn bounded_expression_with_reroll_and_explode() {
+        let actual = parse("2d6r!");
+        let expected = single(Dice {
+            count: 2,
+            die: Die::Standard(6),
+            reroll: Some(reroll(1)),
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(6))),
+            ..Default::default()
+        });
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn bounded_expression_with_reroll_and_explode_non_default_thresholds() {
+        let a = parse("2d6r2!5");
+        let b = parse("2d6!5r2");
+        let expected = single(Dice {
+            count: 2,
+            die: Die::Standard(6),
+            reroll: Some(reroll(2)),
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(5))),
+            ..Default::default()
+        });
+
+        assert_eq!(a, expected);
+        assert_eq!(b, expected);
+    }
+
+    #[test]
+    fn bounded_expression_with_repeated_advantage() {
+        let expected = single(Dice {
+            advantage: StrategyModifier::Advantage,
+            extra_rolls: 1,
+            ..dice(1, 20)
+        });
+        assert_eq!(parse("aad20"), expected);
+        assert_eq!(parse("aA20"), expected);
+
+        let expected = single(Dice {
+            advantage: StrategyModifier::Disadvantage,
+            extra_rolls: 2,
+            ..dice(2, 6)
+        });
+        assert_eq!(parse("sss2d6"), expected);
+
+        assert!(ExpressionParser::new().parse("asd20").is_err());
+    }
+
+    #[test]
+    fn advantage_on_totals() {
+        let actual = parse("a(2d6+3)");
+        let expected = Node::Strategy(
+            StrategyModifier::Advantage,
+            2,
+            Box::new(Node::binary(
+                Operator::Add,
+                Node::Dice(dice(2, 6)),
+                Node::Constant(3),
+            )),
+        );
+        assert_eq!(actual.root, expected);
+
+        let actual = parse("ss(d20)+1");
+        let expected = Node::binary(
+            Operator::Add,
+            Node::Strategy(
+                StrategyModifier::Disadvantage,
+                3,
+                Box::new(Node::Dice(dice(1, 20))),
+            ),
+            Node::Constant(1),
+        );
+        assert_eq!(actual.root, expected);
+
+        assert!(ExpressionParser::new().parse("a(2d6").is_err());
+    }
+
+    #[test]
+    fn clamps() {
+        let expected = single(Dice {
+            clamp: Some(Clamp {
+                min: Some(2),
+                max: Some(5),
+            }),
+            ..dice(2, 6)
+        });
+        assert_eq!(parse("2d6min2max5"), expected);
+        assert_eq!(parse("2d6max5min2"), expected);
+
+        let actual = parse("(1d6-3)min0");
+        let expected = Node::Clamp(
+            Clamp {
+                min: Some(0),
+                max: None,
+            },
+            Box::new(Node::binary(
+                Operator::Subtract,
+                Node::Dice(dice(1, 6)),
+                Node::Constant(3),
+            )),
+        );
+        assert_eq!(actual.root, expected);
+
+        let parser = ExpressionParser::new();
+        for text in ["d20min10min3", "d20min", "d6min5max2", "(d6)max"] {
+            assert!(parser.parse(text).is_err(), "{text}");
+        }
+        assert!(parser.parse("(d6-9)max-2").is_ok());
+    }
+
+    #[test]
+    fn bounded_expression_with_compound_and_penetrate() {
+        let cases = [
+            ("2d6!!", Explode::Compound(Comparison::GreaterOrEqual(6))),
+            ("2d6!!5", Explode::Compound(Comparison::GreaterOrEqual(5))),
+            ("2d6!p", Explode::Penetrate(Comparison::GreaterOrEqual(6))),
+            ("2d6!p5", Explode::Penetrate(Comparison::GreaterOrEqual(5))),
+        ];
+
+        for (text, explode) in cases {
+            let expected = single(Dice {
+                explode: Some(explode),
+                ..dice(2, 6)
+            });
+            assert_eq!(parse(text), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn bounded_expression_with_conditions() {
+        let cases = [
+            ("2d6r<3", Comparison::Less(3), false),
+            ("2d6r=1", Comparison::Equal(1), false),
+            ("2d6r>5", Comparison::Greater(5), false),
+            ("2d6ro", Comparison::LessOrEqual(1), true),
+            ("2d6ro2", Comparison::LessOrEqual(2), true),
+            ("2d6ro<3", Comparison::Less(3), true),
+        ];
+
+        for (text, condition, once) in cases {
+            let expected = single(Dice {
+                reroll: Some(Reroll { condition, once }),
+                ..dice(2, 6)
+            });
+            assert_eq!(parse(text), expected, "{text}");
+        }
+
+        let expected = single(Dice {
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(9))),
+            ..dice(2, 10)
+        });
+        assert_eq!(parse("2d10!>=9"), expected);
+    }
+
+    #[test]
+    fn conditions_are_not_pool_thresholds() {
+        let actual = parse("6d10!>9>=8");
+        let expected = single(Dice {
+            explode: Some(Explode::Standard(Comparison::Greater(9))),
+            pool: Some(Pool {
+                success: Comparison::GreaterOrEqual(8),
+                failure: None,
+            }),
+            ..dice(6, 10)
+        });
+        assert_eq!(actual, expected);
+
+        let actual = parse("2d6r<3");
+        assert!(matches!(actual.root, Node::Dice(dice) if dice.pool.is_none()));
+        assert!(ExpressionParser::new().parse("2d6r<").is_err());
+    }
+
+    #[test]
+    fn bounded_expression_with_advantage() {
+        let a = parse("a20");
+        let b = parse("a1d20");
+        let c = parse("ad20");
+
+        let expected = single(Dice {
+            count: 1,
+            die: Die::Standard(20),
+            advantage: StrategyModifier::Advantage,
+            ..Default::default()
+        });
+
+        assert_eq!(a, expected);
+        assert_eq!(b, expected);
+        assert_eq!(c, expected);
+    }
+
+    #[test]
+    fn bounded_expression_with_disadvantage() {
+        let a = parse("s20");
+        let b = parse("s1d20");
+
+        let expected = single(Dice {
+            count: 1,
+            die: Die::Standard(20),
+            advantage: StrategyModifier::Disadvantage,
+            ..Default::default()
+        });
+
+        assert_eq!(a, expected);
+        assert_eq!(b, expected);
+    }
+
+    #[test]
+    fn multi_term_expression() {
+        let actual = parse("2d6+1d4!+3");
+        let exploding = Dice {
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(4))),
+            ..dice(1, 4)
+        };
+        let expected = Node::binary(
+            Operator::Add,
+            Node::binary(Operator::Add, Node::Dice(dice(2, 6)), Node::Dice(exploding)),
+            Node::Constant(3),
+        );
+
+        assert_eq!(actual.root, expected);
+    }
+
+    #[test]
+    fn multi_term_expression_with_several_modifiers() {
+        let actual = parse("d20+5-2+1d4");
+        let expected = Node::binary(
+            Operator::Add,
+            Node::binary(
+                Operator::Subtract,
+                Node::binary(Operator::Add, Node::Dice(dice(1, 20)), Node::Constant(5)),
+                Node::Constant(2),
+            ),
+            Node::Dice(dice(1, 4)),
+        );
+
+        assert_eq!(actual.root, expected);
+    }
+
+    #[test]
+    fn arithmetic_precedence() {
+        let actual = parse("(2d6+3)*2-1d8/2");
+        let expected = Node::binary(
+            Operator::Subtract,
+            Node::binary(
+                Operator::Multiply,
+                Node::binary(Operator::Add, Node::Dice(dice(2, 6)), Node::Constant(3)),
+                Node::Constant(2),
+            ),
+            Node::binary(
+                Operator::Divide(Rounding::Down),
+                Node::Dice(dice(1, 8)),
+                Node::Constant(2),
+            ),
+        );
+
+        assert_eq!(actual.root, expected);
+    }
+
+    #[test]
+    fn division_rounding_up() {
+        let actual = parse("1d8/^2");
+        let expected = Node::binary(
+            Operator::Divide(Rounding::Up),
+            Node::Dice(dice(1, 8)),
+            Node::Constant(2),
+        );
+
+        assert_eq!(actual.root, expected);
+    }
+
+    #[test]
+    fn leading_number_multiplied_is_constant() {
+        let actual = parse("2*1d6");
+        let expected = Node::binary(
+            Operator::Multiply,
+            Node::Constant(2),
+            Node::Dice(dice(1, 6)),
+        );
+
+        assert_eq!(actual.root, expected);
+    }
+
+    #[test]
+    fn functions() {
+        let actual = parse("max(1d6, 1d8)+1");
+        let expected = Node::binary(
+            Operator::Add,
+            Node::Function(
+                Function::Max,
+                vec![Node::Dice(dice(1, 6)), Node::Dice(dice(1, 8))],
+            ),
+            Node::Constant(1),
+        );
+        assert_eq!(actual.root, expected);
+
+        let actual = parse("abs(2-1d6)");
+        let expected = Node::Function(
+            Function::Abs,
+            vec![Node::binary(
+                Operator::Subtract,
+                Node::Constant(2),
+                Node::Dice(dice(1, 6)),
+            )],
+        );
+        assert_eq!(actual.root, expected);
+
+        let parser = ExpressionParser::new();
+        for text in ["abs(1d6,2)", "min()", "max(1d6", "floor 3d6", "max(1d6,)"] {
+            assert!(parser.parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn floor_and_ceil_round_divisions() {
+        assert_eq!(parse("floor(3d6/2)"), parse("3d6/2"));
+        assert_eq!(parse("ceil(3d6/2)"), parse("3d6/^2"));
+        assert_eq!(parse("ceil(3d6/2)+1d8/2"), parse("3d6/^2+1d8/2"));
+        assert_eq!(parse("ceil(floor(3d6/2)/3)"), parse("(3d6/2)/^3"));
+
+        // Only the division itself is rounded, so sums of divisions aren't floor or ceil.
+        let parser = ExpressionParser::new();
+        for text in ["ceil(1d4/2+1d4/2)", "floor(3d6)", "ceil(2*(1d4/2))"] {
+            assert!(parser.parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn bad_arithmetic() {
+        let parser = ExpressionParser::new();
+        for text in [
+            "(2d6+3",
+            "2d6+3)",
+            "2d6+",
+            "2d6*/2",
+            "1d6/0",
+            "1d6/(1d2-1)",
+            "()",
+        ] {
+            assert!(parser.parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_and_unknown_input() {
+        let cases = [
+            ("2d6foo", 3),
+            ("2d6rr", 4),
+            ("2d6+3)", 5),
+            ("2d6+", 4),
+            ("4d6kh3kh2", 6),
+            ("1+3r", 3),
+            ("2d6>=", 5),
+            ("d{1,2", 5),
+            ("d{1,,2}", 1),
+        ];
+
+        for (text, start) in cases {
+            match ExpressionParser::new().parse(text) {
+                Err(ExpressionError::Syntax { span, .. }) => {
+                    assert_eq!(start, span.start, "{text}")
+                }
+                other => panic!("{text}: expected a syntax error, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn only_syntax_errors_are_unparsable() {
+        let error = crate::error::Error::from(ExpressionParser::new().parse("2d6+").unwrap_err());
+        assert_eq!(
+            "Unable to parse expression: expected a number, dice, '(' or '{' at position 5",
+            error.to_string()
+        );
+
+        let error = crate::error::Error::from(ExpressionError::UnknownVariable("str".into()));
+        assert_eq!("Unknown variable: @str", error.to_string());
+        let error = crate::error::Error::from(ExpressionError::Overflow);
+        assert_eq!("Result out of range", error.to_string());
+    }
+
+    #[test]
+    fn whitespace_between_tokens() {
+        assert_eq!(parse("d20 + 5"), parse("d20+5"));
+        assert_eq!(parse(" 4d6 kh3 "), parse("4d6kh3"));
+    }
+
+    #[test]
+    fn bounded_expression_with_keep_drop() {
+        let cases = [
+            ("4d6kh3", 4, 6, KeepDrop::KeepHighest(3)),
+            ("4d6k3", 4, 6, KeepDrop::KeepHighest(3)),
+            ("2d20kl1", 2, 20, KeepDrop::KeepLowest(1)),
+            ("2d20kl", 2, 20, KeepDrop::KeepLowest(1)),
+            ("3d8dh1", 3, 8, KeepDrop::DropHighest(1)),
+            ("5d10dl2", 5, 10, KeepDrop::DropLowest(2)),
+        ];
+
+        for (text, count, max, keep_drop) in cases {
+            let expected = single(Dice {
+                keep_drop: Some(keep_drop),
+                ..dice(count, max)
+            });
+            assert_eq!(parse(text), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn bounded_expression_with_pool() {
+        let actual = parse("10d10>=8");
+        let expected = single(Dice {
+            pool: Some(Pool {
+                success: Comparison::GreaterOrEqual(8),
+                failure: None,
+            }),
+            ..dice(10, 10)
+        });
+        assert_eq!(actual, expected);
+
+        let actual = parse("6d6>4f1");
+        let expected = single(Dice {
+            pool: Some(Pool {
+                success: Comparison::Greater(4),
+                failure: Some(Comparison::LessOrEqual(1)),
+            }),
+            ..dice(6, 6)
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn pool_rejects_keep_drop() {
+        assert!(ExpressionParser::new().parse("6d10kh3>=8").is_err());
+    }
+
+    #[test]
+    fn wod_pools() {
+        let expected = single(Dice {
+            reroll: Some(Reroll {
+                condition: Comparison::Less(8),
+                once: true,
+            }),
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(9))),
+            pool: Some(Pool {
+                success: Comparison::GreaterOrEqual(8),
+                failure: None,
+            }),
+            system: Some(System::Wod),
+            ..dice(7, 10)
+        });
+        assert_eq!(parse("7wod9r"), expected);
+
+        let chance = single(Dice {
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(10))),
+            pool: Some(Pool {
+                success: Comparison::GreaterOrEqual(10),
+                failure: None,
+            }),
+            system: Some(System::Chance),
+            ..dice(1, 10)
+        });
+        assert_eq!(parse("0wod"), chance);
+
+        assert!(ExpressionParser::new().parse("7wod7").is_err());
+        assert!(ExpressionParser::new().parse("wod").is_err());
+    }
+
+    #[test]
+    fn shadowrun_pools() {
+        let expected = single(Dice {
+            pool: Some(Pool {
+                success: Comparison::GreaterOrEqual(5),
+                failure: None,
+            }),
+            system: Some(System::Shadowrun),
+            ..dice(12, 6)
+        });
+        assert_eq!(parse("12sr"), expected);
+
+        let Node::Dice(edge) = parse("12sr!").root else {
+            panic!("expected dice");
+        };
+        assert_eq!(
+            Some(Explode::Standard(Comparison::GreaterOrEqual(6))),
+            edge.explode
+        );
+    }
+
+    #[test]
+    fn blades_rolls() {
+        let expected = single(Dice {
+            keep_drop: Some(KeepDrop::KeepHighest(1)),
+            system: Some(System::Blades),
+            ..dice(3, 6)
+        });
+        assert_eq!(parse("bitd3"), expected);
+        assert_eq!(parse("bitd 3"), expected);
+
+        let expected = single(Dice {
+            keep_drop: Some(KeepDrop::KeepLowest(1)),
+            system: Some(System::BladesZero),
+            ..dice(2, 6)
+        });
+        assert_eq!(parse("bitd0"), expected);
+        assert!(ExpressionParser::new().parse("bitd").is_err());
+    }
+
+    #[test]
+    fn savage_worlds_trait_rolls() {
+        let die = |max| {
+            Node::Dice(Dice {
+                explode: Some(Explode::Standard(Comparison::GreaterOrEqual(max))),
+                system: Some(System::SavageWorlds),
+                ..dice(1, max)
+            })
+        };
+        let expected = Node::Function(Function::Max, vec![die(8), die(6)]);
+        assert_eq!(parse("sw8").root, expected);
+        assert_eq!(
+            parse("sw8+1").root,
+            Node::Binary(
+                Operator::Add,
+                Box::new(expected),
+                Box::new(Node::Constant(1))
+            )
+        );
+
+        for input in ["sw", "sw1", "swd8"] {
+            assert!(ExpressionParser::new().parse(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn fudge_and_percentile_dice() {
+        let fudge = Dice {
+            count: 4,
+            die: Die::Fudge,
+            ..Default::default()
+        };
+        assert_eq!(parse("4dF"), single(fudge.clone()));
+        assert_eq!(parse("4df"), single(fudge));
+
+        let percentile = Dice {
+            count: 1,
+            die: Die::Percentile,
+            ..Default::default()
+        };
+        assert_eq!(parse("d%"), single(percentile));
+    }
+
+    #[test]
+    fn fudge_thresholds_default_to_faces() {
+        let actual = parse("4dF!r");
+        let expected = single(Dice {
+            count: 4,
+            die: Die::Fudge,
+            reroll: Some(reroll(-1)),
+            explode: Some(Explode::Standard(Comparison::GreaterOrEqual(1))),
+            ..Default::default()
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn custom_faces() {
+        let actual = parse("d{1,1,2,2,3,4}");
+        let faces = [1, 1, 2, 2, 3, 4].map(|value| Face { value, label: None });
+        let expected = single(Dice {
+            count: 1,
+            die: Die::Custom(faces.to_vec()),
+            ..Default::default()
+        });
+        assert_eq!(actual, expected);
+
+        let actual = parse("2d{-1,0,1}-1");
+        let Node::Binary(Operator::Subtract, lhs, _) = actual.root else {
+            panic!("{:?}", actual.root);
+        };
+        assert!(matches!(&*lhs, Node::Dice(dice) if dice.count == 2));
+    }
+
+    #[test]
+    fn named_faces() {
+        let actual = parse("d{miss,hit=1,crit=2}");
+        let Node::Dice(dice) = actual.root else {
+            panic!("expected dice");
+        };
+        assert_eq!(
+            dice.die,
+            Die::Custom(vec![
+                Face {
+                    value: 0,
+                    label: Some("miss".into())
+                },
+                Face {
+                    value: 1,
+                    label: Some("hit".into())
+                },
+                Face {
+                    value: 2,
+                    label: Some("crit".into())
+                },
+            ])
+        );
+        assert_eq!(dice.reroll, None, "names must not be read as modifiers");
+
+        // A lone name refers to a stored die; a one-face die needs its value spelled out.
+        let die = |text| match parse(text).root {
+            Node::Dice(dice) => dice.die,
+            _ => panic!("expected dice"),
+        };
+        assert_eq!(die("d{hit}"), Die::Named("hit".into()));
+        let one_face = Face {
+            value: 0,
+            label: Some("hit".into()),
+        };
+        assert_eq!(die("d{hit=0}"), Die::Custom(vec![one_face]));
+    }
+
+    #[test]
+    fn faces_sharing_a_value() {
+        let faces = Face::parse_table("miss,blank,hit=1").unwrap();
+        assert_eq!(faces[0].value, faces[1].value);
+        assert!(Face::parse_table("miss,miss,hit=1").is_ok());
+    }
+
+    #[test]
+    fn named_die_resolves_from_profile() {
+        let mut profile = Profile::default();
+        profile.dice.insert(
+            "combat".into(),
+            Face::parse_table("miss,hit=1,crit=2").unwrap(),
+        );
+
+        let expression = parse("3d{combat}");
+        assert_eq!(
+            single(Dice {
+                count: 3,
+                die: Die::Named("combat".into()),
+                ..Default::default()
+            }),
+            expression
+        );
+
+        let resolved = expression.resolve(&profile).unwrap();
+        assert!(matches!(&resolved.root, Node::Dice(dice) if matches!(dice.die, Die::Custom(_))));
+        assert!(parse("d{other}").resolve(&profile).is_err());
+        assert!(ExpressionParser::new().parse("3d{combat}!").is_err());
+    }
+
+    #[test]
+    fn rejects_endless_rerolls_and_explosions() {
+        let parser = ExpressionParser::new();
+        for text in [
+            "1d1!",
+            "1d6r6",
+            "1d6r<7",
+            "d6!>=1",
+            "dF!>=-1",
+            "d6ro6!>=1",
+            "d6r5!",
+            "d{2,2}!",
+        ] {
+            assert!(parser.parse(text).is_err(), "{text}");
+        }
+        for text in ["1d6ro6", "1d2!2", "d6r4!", "d{1,2}!"] {
+            assert!(parser.parse(text).is_ok(), "{text}");
+        }
+
+        let mut profile = Profile::default();
+        profile
+            .dice
+            .insert("coin".into(), Face::parse_table("1,1").unwrap());
+        assert!(parse("d{coin}!1").resolve(&profile).is_err());
+        assert!(parse("d{coin}r1").resolve(&profile).is_err());
+    }
+
+    #[test]
+    fn rejects_dice_without_faces() {
+        let parser = ExpressionParser::new();
+        for text in ["1d0", "d0", "0", "2d0+1"] {
+            let error = parser.parse(text).unwrap_err().to_string();
+            assert!(error.contains("at least one face"), "{text}: {error}");
+        }
+    }
+
+    #[test]
+    fn variables_resolve_from_profile() {
+        let expression = parse("d20+@str+@prof");
+        let expected = Node::binary(
+            Operator::Add,
+            Node::binary(
+                Operator::Add,
+                Node::Dice(dice(1, 20)),
+                Node::Variable("str".into()),
+            ),
+            Node::Variable("prof".into()),
+        );
+        assert_eq!(expression.root, expected);
+
+        let mut profile = Profile::default();
+        profile.variables.insert("str".into(), 4);
+        assert!(matches!(
+            expression.resolve(&profile),
+            Err(ExpressionError::UnknownVariable(name)) if name == "prof"
+        ));
+
+        // The same stored expression picks up new values each time it's resolved.
+        profile.variables.insert("prof".into(), 2);
+        assert_close(expression.resolve(&profile).unwrap().average_result(), 16.5);
+        profile.variables.insert("str".into(), 5);
+        assert_close(expression.resolve(&profile).unwrap().average_result(), 17.5);
+
+        let mut realizer = MockRealizer::new(vec![10]);
+        let resolved = expression.resolve(&profile).unwrap();
+        assert_eq!(17, realizer.realize(&resolved).unwrap().sum());
+
+        // A divisor with a variable is checked once the variable's value is known.
+        let divided = parse("1d6/@str");
+        assert!(divided.resolve(&profile).is_ok());
+        profile.variables.insert("str".into(), 0);
+        assert!(divided.resolve(&profile).is_err());
+        assert!(parse("1d6/(@str-@prof+2)").resolve(&profile).is_err());
+        assert!(parse("1d6/max(@str,1)").resolve(&profile).is_ok());
+    }
+
+    #[test]
+    fn tagged_terms() {
+        let expression = parse("2d6[fire]+1d8[ slashing ]");
+        let expected = Node::binary(
+            Operator::Add,
+            Node::Tagged("fire".into(), Box::new(Node::Dice(dice(2, 6)))),
+            Node::Tagged("slashing".into(), Box::new(Node::Dice(dice(1, 8)))),
+        );
+        assert_eq!(expression.root, expected);
+        assert_close(expression.average_result(), 11.5);
+
+        assert!(ExpressionParser::new().parse("2d6[fire").is_err());
+        assert!(ExpressionParser::new().parse("2d6[]").is_err());
+        assert!(ExpressionParser::new().parse("2d6[1st]").is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_dice() {
+        let mut profile = Profile::default();
+        assert!(matches!(
+            parse("999999999d999999999").resolve(&profile),
+            Err(ExpressionError::TooManyDice(999999999, _))
+        ));
+        assert!(parse("5000d6+5000d6").resolve(&profile).is_ok());
+        assert!(parse("5001d6+5000d6").resolve(&profile).is_err());
+
+        profile.limits.max_dice = 3;
+        assert!(parse("3d6").resolve(&profile).is_ok());
+        assert!(parse("4d6").resolve(&profile).is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_faces() {
+        let mut profile = Profile::default();
+        assert!(parse("d1000000").resolve(&profile).is_ok());
+        assert!(matches!(
+            parse("1d100/1d1000000000").resolve(&profile),
+            Err(ExpressionError::TooManyFaces(1000000000, 1000000))
+        ));
+        assert!(parse("sw2147483647").resolve(&profile).is_err());
+
+        profile.limits.max_faces = 6;
+        assert!(parse("3d6").resolve(&profile).is_ok());
+        assert!(parse("d8").resolve(&profile).is_err());
+        assert!(parse("dF+d%").resolve(&profile).is_ok());
+    }
+
+    #[test]
+    fn extreme_thresholds() {
+        let mut profile = Profile::default();
+        profile.limits.max_faces = i32::MAX as i64;
+        let average = |text| parse(text).resolve(&profile).unwrap().average_result();
+        assert_close(average("d2147483647"), 1073741824.0);
+        assert_close(average("d6!>2147483647"), 3.5);
+        assert_close(average("d2147483647r<2147483647"), 2147483647.0);
+        let chance = parse("d2147483647 vs 2147483647")
+            .resolve(&profile)
+            .unwrap();
+        assert_close(chance.success_chance().unwrap(), 1.0 / 2147483647.0);
+
+        // A compounded total that could outgrow a single value is refused.
+        assert!(matches!(
+            parse("d{2147483647,1}!!").resolve(&profile),
+            Err(ExpressionError::Overflow)
+        ));
+        assert!(parse("d{10000000,1}!!").resolve(&profile).is_ok());
+        profile.limits.max_explosions = 1000;
+        assert!(parse("d{10000000,1}!!").resolve(&profile).is_err());
+    }
+
+    #[test]
+    fn bounds_of_huge_dice() {
+        // Bounds are worked out from the thresholds, without listing every face.
+        let bounds = |text| parse(text).root.bounds();
+        assert_eq!(bounds("d2147483647"), 1..=2147483647);
+        assert_eq!(bounds("3d2147483647r<10"), 30..=3 * 2147483647);
+        assert_eq!(bounds("d2147483647min10max20"), 10..=20);
+        assert_eq!(bounds("5d2147483647>=10f1"), -5..=5);
+        assert_eq!(bounds("5d2147483647=10"), 0..=5);
+        assert_close(
+            parse("5d2147483647=10").average_result(),
+            5.0 / 2147483647.0,
+        );
+    }
+
+    #[test]
+    fn realize_explosion_limit() {
+        let mut profile = Profile::default();
+        profile.limits.max_explosions = 2;
+        let expression = parse("1d6!").resolve(&profile).unwrap();
+
+        let mut realizer = MockRealizer::new(vec![6, 6, 6]);
+        assert_eq!(18, realizer.realize(&expression).unwrap().sum());
+    }
+
+    #[test]
+    fn realize_overflow() {
+        let max = i32::MAX;
+        let mut realizer = MockRealizer::new(vec![max, max, max]);
+        let expression = parse("d2147483647*d2147483647*d2147483647");
+        assert!(matches!(
+            realizer.realize(&expression),
+            Err(ExpressionError::Overflow)
+        ));
+
+        let mut realizer = MockRealizer::new(vec![max, max]);
+        let expression = parse("d2147483647!!");
+        assert!(matches!(
+            realizer.realize(&expression),
+            Err(ExpressionError::Overflow)
+        ));
+
+        // Sums are wider than any one die.
+        let mut realizer = MockRealizer::new(vec![max, max]);
+        let result = realizer.realize(&parse("2d2147483647")).unwrap();
+        assert_eq!(2 * max as i64, result.sum());
+    }
+
+    #[test]
+    fn realize_bounded_expression() {
+        let mut realizer = MockRealizer::new(vec![2, 3]);
+        let expression = parse("2d6");
+        assert_eq!(5, realizer.realize(&expression).unwrap().sum());
+    }
+
+    #[test]
+    fn realize_advantage() {
+        let mut realizer = MockRealizer::new(vec![2, 20]);
+        let expression = parse("a20");
+        assert_eq!(20, realizer.realize(&expression).unwrap().sum());
+    }
+
+    #[test]
+    fn realize_disadvantage() {
+        let mut realizer = MockRealizer::new(vec![20, 2]);
+        let expression = parse("s20");
+        assert_eq!(2, realizer.realize(&expression).unwrap().sum());
+    }
+
+    #[test]
+    fn realize_repeated_advantage() {
+        let mut realizer = MockRealizer::new(vec![3, 19, 7]);
+        assert_eq!(19, realizer.realize(&parse("aad20")).unwrap().sum());
+
+        let mut realizer = MockRealizer::new(vec![3, 19, 1]);
+        assert_eq!(1, realizer.realize(&parse("ssd20")).unwrap().sum());
+
+        // Only the first die is rolled with disadvantage.
+        let mut realizer = MockRealizer::new(vec![6, 5, 4, 6]);
+        assert_eq!(10, realizer.realize(&parse("ss2d6")).unwrap().sum());
+    }
+
+    #[test]
+    fn realize_advantage_on_totals() {
+        let mut realizer = MockRealizer::new(vec![1, 2, 6, 5]);
+        let result = realizer.realize(&parse("a(2d6+3)")).unwrap();
+        assert_eq!(14, result.sum());
+
+        let dropped: Vec<_> = result
+            .results()
+            .map(|(highlight, _)| matches!(highlight, Highlight::Dropped))
+            .collect();
+        assert_eq!(vec![true, true, false, false], dropped);
+
+        let mut realizer = MockRealizer::new(vec![6, 5, 1, 2, 3, 3]);
+        assert_eq!(3, realizer.realize(&parse("ss(2d6)")).unwrap().sum());
+    }
+
+    #[test]
+    fn realize_clamps() {
+        let mut realizer = MockRealizer::new(vec![3, 17]);
+        let result = realizer.realize(&parse("2d20min10")).unwrap();
+        assert_eq!(27, result.sum());
+
+        let labels: Vec<_> = result
+            .labelled_results()
+            .map(|(_, _, label)| label)
+            .collect();
+        assert_eq!(vec!["10 (3)", "17"], labels);
+
+        // Explosions go by the roll, before it's clamped.
+        let mut realizer = MockRealizer::new(vec![6, 1]);
+        assert_eq!(6, realizer.realize(&parse("d6!max4min2")).unwrap().sum());
+
+        let mut realizer = MockRealizer::new(vec![2]);
+        let result = realizer.realize(&parse("(1d6-5)min0")).unwrap();
+        assert_eq!(0, result.sum());
+        assert_eq!(vec![(-3, 0)], result.clamped_totals());
+    }
+
+    #[test]
+    fn realize_subtracted_dice() {
+        let mut realizer = MockRealizer::new(vec![15, 4, 1, 6]);
+        let result = realizer.realize(&parse("1d20-1d4")).unwrap();
+        assert_eq!(11, result.sum());
+
+        // The highest roll on a subtracted die is the worst result for the total.
+        let results: Vec<_> = result.labelled_results().collect();
+        assert!(matches!(results[0], (Highlight::Normal, false, _)));
+        assert!(matches!(results[1], (Highlight::Low, true, _)));
+
+        let result = realizer.realize(&parse("-(1d6-1d6)")).unwrap();
+        assert_eq!(5, result.sum());
+        let signs: Vec<_> = result
+            .labelled_results()
+            .map(|(_, negated, _)| negated)
+            .collect();
+        assert_eq!(vec![true, false], signs);
+        assert!(matches!(
+            result.results().next(),
+            Some((Highlight::High, 1))
+        ));
+    }
+
+    #[test]
+    fn realize_functions() {
+        let mut realizer = MockRealizer::new(vec![3, 5]);
+        let result = realizer.realize(&parse("max(1d6, 1d8)")).unwrap();
+        assert_eq!(5, result.sum());
+
+        let dropped: Vec<_> = result
+            .results()
+            .map(|(highlight, _)| matches!(highlight, Highlight::Dropped))
+            .collect();
+        assert_eq!(vec![true, false], dropped);
+
+        let mut realizer = MockRealizer::new(vec![12, 3, 7]);
+        assert_eq!(
+            5,
+            realizer
+                .realize(&parse("min(d20,d20,d20)+2"))
+                .unwrap()
+                .sum()
+        );
+
+        let mut realizer = MockRealizer::new(vec![2, 5]);
+        assert_eq!(3, realizer.realize(&parse("abs(1d6-1d6)")).unwrap().sum());
+    }
+
+    #[test]
+    fn groups() {
+        let expected = Node::Group(
+            vec![Node::Dice(dice(4, 6)), Node::Dice(dice(4, 6))],
+            Some(KeepDrop::KeepHighest(1)),
+        );
+        assert_eq!(parse("{4d6, 4d6}kh1").root, expected);
+        assert_eq!(
+            parse("{2d6+1d8}kh2").root,
+            Node::Pooled(vec![dice(2, 6), dice(1, 8)], KeepDrop::KeepHighest(2))
+        );
+
+        // With one member, keep/drop needs dice to pool.
+        assert!(ExpressionParser::new().parse("{2d6+3}kh1").is_err());
+        assert!(ExpressionParser::new().parse("{2d6*2}kh1").is_err());
+        assert!(ExpressionParser::new().parse("{2d6,}").is_err());
+        assert!(ExpressionParser::new().parse("{2d6").is_err());
+    }
+
+    #[test]
+    fn repetition() {
+        let expression = parse("6#4d6kh3");
+        assert_eq!(6, expression.repetitions());
+        assert_eq!(1, parse("4d6kh3").repetitions());
+
+        // The die shorthand still applies after the count.
+        assert_eq!(parse("3#20").root, count_max(1, 20).root);
+        assert_eq!(parse("2#d20+5 vs 15").target(), Some(15));
+
+        assert!(ExpressionParser::new().parse("0#d6").is_err());
+        assert!(ExpressionParser::new().parse("2#").is_err());
+        assert!(ExpressionParser::new().parse("2#3#d6").is_err());
+        assert!(ExpressionParser::new().parse("d6#2").is_err());
+
+        // Every roll counts towards the limit on dice.
+        let mut profile = Profile::default();
+        profile.limits.max_dice = 20;
+        assert!(parse("5#4d6").resolve(&profile).is_ok());
+        assert!(parse("6#4d6").resolve(&profile).is_err());
+    }
+
+    #[test]
+    fn targets() {
+        let expression = parse("d20+5 vs 15");
+        assert_eq!(Some(15), expression.target());
+        assert_close(expression.success_chance().unwrap(), 0.55);
+        assert_eq!(None, parse("d20+5").success_chance());
+        assert_eq!(Some(-2), parse("1d4-3 vs -2").target());
+
+        // A target from --dc doesn't replace one in the expression.
+        assert_eq!(Some(15), expression.clone().or_target(Some(10)).target());
+        assert_eq!(Some(10), parse("d20").or_target(Some(10)).target());
+
+        assert!(ExpressionParser::new().parse("d20 vs").is_err());
+        assert!(ExpressionParser::new().parse("d20 vs 15+1").is_err());
+        assert!(ExpressionParser::new().parse("d20 vs @dc").is_err());
+    }
+
+    #[test]
+    fn single_die_chance_matches_distribution() {
+        for text in [
+            "d20", "ad20", "Ad20", "aad20", "d20r<3", "ad20r<=2", "d6!>4", "3d6",
+        ] {
+            let expression = parse(text);
+            let distribution = expression.root.distribution();
+            for value in [-1, 0, 1, 2, 3, 6, 11, 20, 21] {
+                let expected = distribution.at_least(value) / distribution.mass();
+                let chance = parse(&format!("{text} vs {value}"))
+                    .success_chance()
+                    .unwrap();
+                assert_close(chance, expected);
+            }
+        }
+
+        // Huge dice aren't listed face by face.
+        assert_close(parse("d1000000 vs 5").success_chance().unwrap(), 0.999996);
+        assert_close(parse("ad1000000 vs 500001").success_chance().unwrap(), 0.75);
+    }
+
+    #[test]
+    fn bands() {
+        let expression = parse("2d6+1 bands");
+        let chances = expression.band_chances().unwrap();
+        let expected = [("miss", 10.0), ("weak hit", 16.0), ("strong hit", 10.0)];
+        for ((name, chance), (expected, ways)) in chances.into_iter().zip(expected) {
+            assert_eq!(name, expected);
+            assert_close(chance, ways / 36.0);
+        }
+        assert_eq!(None, parse("2d6+1").band_chances());
+
+        // A band that can't be reached has no chance, not a negative zero.
+        let d6 = parse("d6 bands");
+        let chances = d6.band_chances().unwrap();
+        assert!(chances.iter().all(|(_, chance)| chance.is_sign_positive()));
+
+        let custom = parse("2d6 vs 7 bands[low,5:mid, 9 : high]");
+        assert_eq!(Some(7), custom.target());
+        let chances = custom.band_chances().unwrap();
+        let names: Vec<_> = chances.into_iter().map(|(name, _)| name).collect();
+        assert_eq!(vec!["low", "mid", "high"], names);
+
+        // Bands are kept when an alias is stored.
+        let stored = serde_json::to_string(&custom).unwrap();
+        assert_eq!(custom, serde_json::from_str(&stored).unwrap());
+
+        for input in [
+            "2d6 bands[]",
+            "2d6 bands[miss]",
+            "2d6 bands[miss,7]",
+            "2d6 bands[miss,x:hit]",
+            "2d6 bands[miss,10:hit,7:weak]",
+            "2d6 bands[miss,7:weak-hit]",
+            "2d6 bands[miss,7:hit",
+            "2d6 bands vs 7",
+            "2d6 bands 3",
+        ] {
+            assert!(ExpressionParser::new().parse(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn critical_ranges() {
+        let actual = parse("d20c19cf<3");
+        let expected = single(Dice {
+            critical: Some(Comparison::GreaterOrEqual(19)),
+            fumble: Some(Comparison::Less(3)),
+            ..dice(1, 20)
+        });
+        assert_eq!(actual, expected);
+
+        // Ranges are kept when an alias is stored.
+        let stored = serde_json::to_string(&actual).unwrap();
+        assert_eq!(actual, serde_json::from_str(&stored).unwrap());
+
+        assert!(ExpressionParser::new().parse("d20c19c20").is_err());
+        assert!(ExpressionParser::new().parse("d{combat}c").is_err());
+    }
+
+    #[test]
+    fn rejects_ranges_on_every_face_or_none() {
+        let parser = ExpressionParser::new();
+        let rejected = [
+            ("1d6c0", 3),
+            ("1d6c7", 3),
+            ("d6cf6", 2),
+            ("d6c6cf>=1", 4),
+            ("dFcf>1", 2),
+        ];
+        for (text, start) in rejected {
+            match parser.parse(text) {
+                Err(ExpressionError::Syntax { span, .. }) => {
+                    assert_eq!(start, span.start, "{text}")
+                }
+                result => panic!("{text}: {result:?}"),
+            }
+        }
+        for text in ["1d6c6", "d20c19cf2", "d6c<2", "dFcf<0", "d{1,3}c2"] {
+            assert!(parser.parse(text).is_ok(), "{text}");
+        }
+
+        let mut profile = Profile::default();
+        profile
+            .dice
+            .insert("coin".into(), Face::parse_table("0,1").unwrap());
+        assert!(parse("d{coin}c1").resolve(&profile).is_ok());
+        assert!(parse("d{coin}c0").resolve(&profile).is_err());
+        assert!(parse("d{coin}cf>1").resolve(&profile).is_err());
+    }
+
+    #[test]
+    fn realize_critical_ranges() {
+        let mut realizer = MockRealizer::new(vec![19, 2, 20, 1, 10, 3]);
+        let expression = parse("d20c19cf2+5");
+
+        let result = realizer.realize(&expression).unwrap();
+        assert!(result.is_critical());
+        assert!(matches!(
+            result.results().next(),
+            Some((Highlight::High, 19))
+        ));
+        assert!(result.notes().contains("critical"));
+
+        let result = realizer.realize(&expression).unwrap();
+        assert!(result.is_fumble());
+        assert!(matches!(result.results().next(), Some((Highlight::Low, 2))));
+        assert!(result.notes().contains("fumble"));
+
+        // Without a range, only the highest and lowest faces count, and are not called out.
+        let result = realizer.realize(&parse("2d20kh1")).unwrap();
+        assert!(result.is_critical());
+        assert_eq!("", result.notes());
+        let result = realizer.realize(&parse("d20+d4")).unwrap();
+        assert!(!result.is_critical() && !result.is_fumble());
+        assert_eq!("", result.notes());
+    }
+
+    #[test]
+    fn realize_checks() {
+        let mut realizer = MockRealizer::new(vec![10, 9]);
+        let expression = parse("d20+5 vs 15");
+        let check = realizer.realize(&expression).unwrap().check().unwrap();
+        assert!(check.passed);
+        assert_eq!(0, check.margin);
+
+        let check = realizer.realize(&expression).unwrap().check().unwrap();
+        assert!(!check.passed);
+        assert_eq!(-1, check.margin);
+    }
+
+    #[test]
+    fn realize_bands() {
+        let mut realizer = MockRealizer::new(vec![3, 2, 3, 3, 6, 3, 1, 1]);
+        let expression = parse("2d6+1 bands");
+        let mut band = || {
+            let result = realizer.realize(&expression).unwrap();
+            result.band().map(|(_, band)| band.to_owned())
+        };
+        assert_eq!(Some("miss"), band().as_deref());
+        assert_eq!(Some("weak hit"), band().as_deref());
+        assert_eq!(Some("strong hit"), band().as_deref());
+        assert_eq!(Some("miss"), band().as_deref());
+
+        let mut realizer = MockRealizer::new(vec![4, 3, 4]);
+        let result = realizer.realize(&parse("1d6")).unwrap();
+        assert!(result.band().is_none());
+        assert_eq!(1, result.sum_row(false).cell_count());
+        assert_eq!(2, result.sum_row(true).cell_count());
+        let result = realizer.realize(&expression).unwrap();
+        assert_eq!(2, result.sum_row(false).cell_count());
+    }
+
+    #[test]
+    fn realize_groups() {
+        let mut realizer = MockRealizer::new(vec![3, 4, 6, 6, 1, 2]);
+        let result = realizer.realize(&parse("{2d6,2d6,2d6}kh1+1")).unwrap();
+        assert_eq!(13, result.sum());
+        assert_eq!(
+            vec![vec![(7, false), (12, true), (3, false)]],
+            result.group_totals()
+        );
+
+        let dropped: Vec<_> = result
+            .results()
+            .map(|(highlight, _)| matches!(highlight, Highlight::Dropped))
+            .collect();
+        assert_eq!(vec![true, true, false, false, true, true], dropped);
+
+        // The dice of a pool are kept or dropped together.
+        let mut realizer = MockRealizer::new(vec![2, 5, 7]);
+        let result = realizer.realize(&parse("{2d6+1d8}kh2")).unwrap();
+        assert_eq!(12, result.sum());
+        let dropped: Vec<_> = result
+            .results()
+            .map(|(highlight, _)| matches!(highlight, Highlight::Dropped))
+            .collect();
+        assert_eq!(vec![true, false, false], dropped);
+    }
+
+    #[test]
+    fn realize_subtotals() {
+        let mut realizer = MockRealizer::new(vec![4, 5, 5, 1, 2]);
+        let result = realizer
+            .realize(&parse("2d6[fire]+1d8[slashing]+3-1d4[fire]+(1d6[cold])*2"))
+            .unwrap();
+        assert_eq!(20, result.sum());
+
+        let subtotals: Vec<_> = result
+            .subtotals()
+            .iter()
+            .map(|subtotal| (subtotal.label.as_str(), subtotal.value))
+            .collect();
+        assert_eq!(vec![("fire", 8), ("slashing", 5), ("cold", 4)], subtotals);
+        assert_eq!(" = 8 fire + 5 slashing + 4 cold + 3", result.notes());
+
+        // max() of two labels belongs to neither.
+        let mut realizer = MockRealizer::new(vec![2, 5]);
+        let result = realizer
+            .realize(&parse("max(1d6[fire],1d6[cold])"))
+            .unwrap();
+        assert!(result.subtotals().is_empty());
+    }
 
-        let mut row = comfy_table::Row::new();
-        row.add_cell(value.sum().into());
+    #[test]
+    fn realize_resistances() {
+        let mut realizer = MockRealizer::new(vec![4, 5, 5, 2]);
+        let mut result = realizer
+            .realize(&parse("2d6[fire]+1d8[slashing]+1d4[cold]+1"))
+            .unwrap();
+        assert_eq!(17, result.sum());
 
-        let mut results = value.results();
-        let mut w = String::new();
+        result
+            .apply_resistances(&["fire".into()], &["cold".into()])
+            .unwrap();
+        assert_eq!(4 + 5 + 4 + 1, result.sum());
 
-        if let Some((highlight, value)) = results.next() {
-            match highlight {
-                Highlight::High => write!(w, "   = {}", value.bright_green()),
-                Highlight::Low => write!(w, "   = {}", value.bright_red()),
-                Highlight::Normal => write!(w, "   = {}", value),
-            }.unwrap();
-        }
-        
-        for (highlight, value) in results {
-            match highlight {
-                Highlight::High => write!(w, " + {}", value.bright_green()),
-                Highlight::Low => write!(w, " + {}", value.bright_red()),
-                Highlight::Normal => write!(w, " + {}", value),
-            }.unwrap();
-        }
+        // Resistance rounds down, and applying again starts from the rolled subtotals.
+        result.apply_resistances(&["slashing".into()], &[]).unwrap();
+        assert_eq!(9 + 2 + 2 + 1, result.sum());
+    }
 
-        row.add_cell(w.into());
-        row
+    #[test]
+    fn realize_reroll() {
+        let mut realizer = MockRealizer::new(vec![2, 3, 5]);
+        let expression = parse("2d6r2");
+        assert_eq!(8, realizer.realize(&expression).unwrap().sum());
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-pub enum Highlight {
-    High,
-    Low,
-    Normal,
-}
+    #[test]
+    fn realize_explode() {
+        let mut realizer = MockRealizer::new(vec![3, 5, 2]);
+        let expression = parse("2d6!5");
+        assert_eq!(10, realizer.realize(&expression).unwrap().sum());
+    }
 
-fn parse_threshold_token(
-    extractor: &impl TokenExtractor,
-    expr: &str,
-    default: i32,
-) -> Result<Option<i32>> {
-    let (is_present, value) = extractor.extract(expr);
+    #[test]
+    fn realize_compound_explode() {
+        let mut realizer = MockRealizer::new(vec![6, 6, 2, 3]);
+        let result = realizer.realize(&parse("2d6!!")).unwrap();
+        assert_eq!(17, result.sum());
+        assert_eq!(
+            vec![14, 3],
+            result.results().map(|(_, x)| x).collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            result.results().next(),
+            Some((Highlight::High, 14))
+        ));
+    }
 
-    if is_present {
-        match value {
-            Some(value) => {
-                let parsed = value
-                    .parse()
-                    .map_err(|e| ExpressionError::BadInteger(expr.into(), e))?;
-                Ok(Some(parsed))
-            }
-            None => Ok(Some(default)),
-        }
-    } else {
-        Ok(None)
+    #[test]
+    fn realize_compound_explode_in_pool() {
+        // The compounded total of 8 is a single success; the 6 alone is not.
+        let mut realizer = MockRealizer::new(vec![6, 2, 6, 1]);
+        let result = realizer.realize(&parse("2d6!!6>=8")).unwrap();
+        assert_eq!(1, result.sum());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::expression::{StrategyModifier, Explode, Expression, ExpressionParser, Realizer, Reroll};
+    #[test]
+    fn realize_penetrate_explode() {
+        // Each extra roll counts one less, but explodes on the face it shows.
+        let mut realizer = MockRealizer::new(vec![6, 6, 1, 4]);
+        let result = realizer.realize(&parse("2d6!p")).unwrap();
+        assert_eq!(15, result.sum());
+        assert_eq!(
+            vec![6, 5, 0, 4],
+            result.results().map(|(_, x)| x).collect::<Vec<_>>()
+        );
+    }
 
     #[test]
-    fn bounded_expression() {
-        let expression = parse("2d6");
-        assert_eq!(count_max(2, 6), expression);
+    fn realize_reroll_once() {
+        // The second roll of 2 stands even though it qualifies again.
+        let mut realizer = MockRealizer::new(vec![1, 2, 4]);
+        let expression = parse("2d6ro<3");
+        assert_eq!(6, realizer.realize(&expression).unwrap().sum());
     }
 
     #[test]
-    fn leading_bounded_expression() {
-        let a = parse("20");
-        let b = parse("d20");
-        let expected = count_max(1, 20);
-        assert_eq!(a, expected);
-        assert_eq!(b, expected);
+    fn realize_reroll_once_with_explosion() {
+        // Each roll in an explosion chain gets its own reroll.
+        let mut realizer = MockRealizer::new(vec![6, 1, 1]);
+        let expression = parse("1d6ro!");
+        assert_eq!(7, realizer.realize(&expression).unwrap().sum());
     }
 
     #[test]
-    fn bounded_expression_with_reroll() {
-        let actual = parse("2d6r");
-        let expected = Expression {
-            count: 2,
-            max: 6,
-            reroll: Some(Reroll(1)),
-            ..Default::default()
-        };
+    fn realize_reroll_high_values() {
+        let mut realizer = MockRealizer::new(vec![6, 5, 3, 2]);
+        let expression = parse("2d6r>4");
+        assert_eq!(5, realizer.realize(&expression).unwrap().sum());
+    }
 
-        assert_eq!(actual, expected);
+    #[test]
+    fn realize_explode_with_condition() {
+        let mut realizer = MockRealizer::new(vec![9, 10, 2, 8]);
+        let expression = parse("2d10!>=9");
+        assert_eq!(29, realizer.realize(&expression).unwrap().sum());
     }
 
     #[test]
-    fn bounded_expression_with_reroll_2() {
-        let actual = parse("2d6r2");
-        let expected = Expression {
-            count: 2,
-            max: 6,
-            reroll: Some(Reroll(2)),
-            ..Default::default()
-        };
+    fn realize_reroll_and_explode() {
+        let mut realizer = MockRealizer::new(vec![1, 2, 5, 6, 3, 4]);
+        let expression = parse("2d6r2!5");
+        assert_eq!(18, realizer.realize(&expression).unwrap().sum());
+    }
 
-        assert_eq!(actual, expected);
+    #[test]
+    fn realize_multi_term() {
+        let mut realizer = MockRealizer::new(vec![2, 3, 4]);
+        let expression = parse("2d6+1d4+3");
+        let result = realizer.realize(&expression).unwrap();
+        assert_eq!(12, result.sum());
+        assert_eq!(
+            vec![2, 3, 4],
+            result.results().map(|(_, x)| x).collect::<Vec<_>>()
+        );
     }
 
     #[test]
-    fn bounded_expression_with_explode() {
-        let actual = parse("2d6!");
-        let expected = Expression {
-            count: 2,
-            max: 6,
-            explode: Some(Explode(6)),
-            ..Default::default()
-        };
+    fn realize_keep_highest() {
+        let mut realizer = MockRealizer::new(vec![3, 1, 5, 6]);
+        let result = realizer.realize(&parse("4d6kh3")).unwrap();
+        assert_eq!(14, result.sum());
 
-        assert_eq!(actual, expected);
+        let dropped: Vec<_> = result
+            .results()
+            .filter(|(highlight, _)| matches!(highlight, Highlight::Dropped))
+            .map(|(_, x)| x)
+            .collect();
+        assert_eq!(vec![1], dropped);
     }
 
     #[test]
-    fn bounded_expression_with_explode_5() {
-        let actual = parse("2d6!5");
-        let expected = Expression {
-            count: 2,
-            max: 6,
-            explode: Some(Explode(5)),
-            ..Default::default()
-        };
+    fn realize_drop_lowest_keeps_ties_stable() {
+        let mut realizer = MockRealizer::new(vec![4, 2, 2, 5, 1]);
+        let result = realizer.realize(&parse("5d10dl2")).unwrap();
+        assert_eq!(11, result.sum());
+    }
 
-        assert_eq!(actual, expected);
+    #[test]
+    fn realize_keep_lowest_with_explosion() {
+        // The exploding die counts as a single die worth 6 + 2.
+        let mut realizer = MockRealizer::new(vec![6, 2, 5]);
+        let result = realizer.realize(&parse("2d6!kl1")).unwrap();
+        assert_eq!(5, result.sum());
     }
 
     #[test]
-    fn bounded_expression_with_reroll_and_explode() {
-        let actual = parse("2d6r!");
-        let expected = Expression {
-            count: 2,
-            max: 6,
-            reroll: Some(Reroll(1)),
-            explode: Some(Explode(6)),
-            ..Default::default()
-        };
+    fn realize_pool() {
+        let mut realizer = MockRealizer::new(vec![5, 6, 1, 3, 2, 6]);
+        let result = realizer.realize(&parse("6d6>4f1")).unwrap();
+        assert_eq!(2, result.sum());
 
-        assert_eq!(actual, expected);
+        let highlights: Vec<_> = result
+            .results()
+            .map(|(highlight, _)| match highlight {
+                Highlight::Success => '+',
+                Highlight::Failure => '-',
+                _ => ' ',
+            })
+            .collect();
+        assert_eq!(vec!['+', '+', '-', ' ', ' ', '+'], highlights);
     }
 
     #[test]
-    fn bounded_expression_with_reroll_and_explode_non_default_thresholds() {
-        let a = parse("2d6r2!5");
-        let b = parse("2d6!5r2");
-        let expected = Expression {
-            count: 2,
-            max: 6,
-            reroll: Some(Reroll(2)),
-            explode: Some(Explode(5)),
-            ..Default::default()
-        };
-
-        assert_eq!(a, expected);
-        assert_eq!(b, expected);
+    fn realize_pool_with_explosion() {
+        // The exploded 10 is its own success.
+        let mut realizer = MockRealizer::new(vec![10, 8, 3]);
+        let result = realizer.realize(&parse("2d10>=8!")).unwrap();
+        assert_eq!(2, result.sum());
     }
 
     #[test]
-    fn bounded_expression_with_advantage() {
-        let a = parse("a20");
-        let b = parse("a1d20");
-        let c = parse("ad20");
+    fn realize_wod_pools() {
+        // The 10 rolls again, and its 9 is a fifth success.
+        let mut realizer = MockRealizer::new(vec![10, 9, 8, 8, 8, 3]);
+        let result = realizer.realize(&parse("5wod")).unwrap();
+        assert_eq!(5, result.sum());
+        assert_eq!(Some(Outcome::ExceptionalSuccess), result.outcome());
 
-        let expected = Expression {
-            count: 1,
-            max: 20,
-            advantage: StrategyModifier::Advantage,
-            ..Default::default()
-        };
+        // Rote rerolls each failure once.
+        let mut realizer = MockRealizer::new(vec![3, 9, 5, 2]);
+        let result = realizer.realize(&parse("2wodr")).unwrap();
+        assert_eq!(1, result.sum());
+        assert_eq!(None, result.outcome());
 
-        assert_eq!(a, expected);
-        assert_eq!(b, expected);
-        assert_eq!(c, expected);
+        let mut realizer = MockRealizer::new(vec![1, 8, 10, 4]);
+        let result = realizer.realize(&parse("0wod")).unwrap();
+        assert_eq!(Some(Outcome::DramaticFailure), result.outcome());
+        let result = realizer.realize(&parse("0wod")).unwrap();
+        assert_eq!((0, None), (result.sum(), result.outcome()));
+        let result = realizer.realize(&parse("0wod")).unwrap();
+        assert_eq!(1, result.sum());
     }
 
     #[test]
-    fn bounded_expression_with_disadvantage() {
-        let a = parse("s20");
-        let b = parse("s1d20");
+    fn realize_shadowrun_pools() {
+        let mut realizer = MockRealizer::new(vec![1, 1, 5, 3, 1, 1, 2, 2, 1, 1, 1, 4]);
+        let result = realizer.realize(&parse("4sr")).unwrap();
+        assert_eq!((1, None), (result.sum(), result.outcome()));
 
-        let expected = Expression {
-            count: 1,
-            max: 20,
-            advantage: StrategyModifier::Disadvantage,
-            ..Default::default()
-        };
+        let result = realizer.realize(&parse("3sr")).unwrap();
+        assert_eq!(Some(Outcome::CriticalGlitch), result.outcome());
 
-        assert_eq!(a, expected);
-        assert_eq!(b, expected);
+        // With edge, the 6 rolls again, but the extra die doesn't count towards a glitch.
+        let mut realizer = MockRealizer::new(vec![1, 1, 6, 1]);
+        let result = realizer.realize(&parse("3sr!")).unwrap();
+        assert_eq!((1, Some(Outcome::Glitch)), (result.sum(), result.outcome()));
     }
 
     #[test]
-    fn realize_bounded_expression() {
-        let mut realizer = MockRealizer::new(vec![2, 3]);
-        let expression = parse("2d6");
-        assert_eq!(5, realizer.realize(&expression).sum());
+    fn realize_blades_rolls() {
+        let mut realizer = MockRealizer::new(vec![6, 2, 6, 6, 5, 5, 4, 1, 3, 3, 2, 6, 6, 6]);
+        let mut outcome = |input| {
+            let result = realizer.realize(&parse(input)).unwrap();
+            let outcome = result.outcome();
+            assert!(result.notes().contains(&outcome.unwrap().to_string()));
+            (result.sum(), outcome)
+        };
+        assert_eq!((6, Some(Outcome::Critical)), outcome("bitd3"));
+        assert_eq!((6, Some(Outcome::FullSuccess)), outcome("bitd2"));
+        assert_eq!((5, Some(Outcome::PartialSuccess)), outcome("bitd1"));
+        assert_eq!((4, Some(Outcome::PartialSuccess)), outcome("bitd2"));
+        assert_eq!((3, Some(Outcome::Bad)), outcome("bitd2"));
+        assert_eq!((2, Some(Outcome::Bad)), outcome("bitd0"));
+        // Two 6s with no dice are only a full success.
+        assert_eq!((6, Some(Outcome::FullSuccess)), outcome("bitd0"));
     }
 
     #[test]
-    fn realize_advantage() {
-        let mut realizer = MockRealizer::new(vec![2, 20]);
-        let expression = parse("a20");
-        assert_eq!(20, realizer.realize(&expression).sum());
+    fn realize_savage_worlds_trait_rolls() {
+        let mut realizer = MockRealizer::new(vec![1, 1, 8, 3, 2, 2, 6, 4, 3, 4, 4, 1, 3]);
+        let result = realizer.realize(&parse("sw8")).unwrap();
+        assert_eq!(
+            (1, Some(Outcome::CriticalFailure)),
+            (result.sum(), result.outcome())
+        );
+
+        // The trait die acing to 11 counts, and is a raise over the default TN of 4.
+        let result = realizer.realize(&parse("sw8")).unwrap();
+        assert_eq!(
+            (11, Some(Outcome::Raises(1))),
+            (result.sum(), result.outcome())
+        );
+
+        // Here the wild die counts instead, and raises are over the given TN.
+        let result = realizer.realize(&parse("sw8 vs 6")).unwrap();
+        assert_eq!(
+            (10, Some(Outcome::Raises(1))),
+            (result.sum(), result.outcome())
+        );
+        assert_eq!(Some(true), result.check().map(|check| check.passed));
+
+        let result = realizer.realize(&parse("sw8 vs 8")).unwrap();
+        assert_eq!((4, None), (result.sum(), result.outcome()));
+
+        let result = realizer.realize(&parse("sw4")).unwrap();
+        assert_eq!((5, None), (result.sum(), result.outcome()));
     }
 
     #[test]
-    fn realize_disadvantage() {
-        let mut realizer = MockRealizer::new(vec![20, 2]);
-        let expression = parse("s20");
-        assert_eq!(2, realizer.realize(&expression).sum());
+    fn realize_arithmetic() {
+        let mut realizer = MockRealizer::new(vec![2, 5, 7]);
+        let expression = parse("(2d6+3)*2-1d8/2");
+        assert_eq!(17, realizer.realize(&expression).unwrap().sum());
     }
 
     #[test]
-    fn realize_reroll() {
-        let mut realizer = MockRealizer::new(vec![2, 3, 5]);
-        let expression = parse("2d6r2");
-        assert_eq!(8, realizer.realize(&expression).sum());
+    fn realize_division_rounding() {
+        let mut realizer = MockRealizer::new(vec![7, 7]);
+        let expression = parse("1d8/2+1d8/^2");
+        assert_eq!(7, realizer.realize(&expression).unwrap().sum());
     }
 
     #[test]
-    fn realize_explode() {
-        let mut realizer = MockRealizer::new(vec![3, 5, 2]);
-        let expression = parse("2d6!5");
-        assert_eq!(10, realizer.realize(&expression).sum());
+    fn realize_fudge() {
+        let mut realizer = MockRealizer::new(vec![1, 2, 3, 3]);
+        let result = realizer.realize(&parse("4dF")).unwrap();
+        assert_eq!(1, result.sum());
+
+        let labels: Vec<_> = result
+            .labelled_results()
+            .map(|(_, _, label)| label)
+            .collect();
+        assert_eq!(vec!["[-]", "[ ]", "[+]", "[+]"], labels);
     }
 
     #[test]
-    fn realize_reroll_and_explode() {
-        let mut realizer = MockRealizer::new(vec![1, 2, 5, 6, 3, 4]);
-        let expression = parse("2d6r2!5");
-        assert_eq!(18, realizer.realize(&expression).sum());
+    fn realize_percentile() {
+        let mut realizer = MockRealizer::new(vec![3, 7, 10, 10, 10, 4]);
+        let result = realizer.realize(&parse("3d%")).unwrap();
+        assert_eq!(37 + 100 + 4, result.sum());
+
+        let labels: Vec<_> = result
+            .labelled_results()
+            .map(|(_, _, label)| label)
+            .collect();
+        assert_eq!(vec!["37 (30+7)", "100 (00+0)", "4 (00+4)"], labels);
+    }
+
+    #[test]
+    fn realize_named_faces() {
+        let mut realizer = MockRealizer::new(vec![3, 2, 1, 3]);
+        let result = realizer.realize(&parse("4d{miss,hit=1,crit=2}")).unwrap();
+        assert_eq!(5, result.sum());
+
+        let labels: Vec<_> = result
+            .labelled_results()
+            .map(|(_, _, label)| label)
+            .collect();
+        assert_eq!(vec!["crit", "hit", "miss", "crit"], labels);
+
+        let tally: Vec<_> = result.tally().into_iter().collect();
+        assert_eq!(vec![("crit", 2), ("hit", 1), ("miss", 1)], tally);
+
+        // Faces worth the same are told apart by which was rolled.
+        let mut realizer = MockRealizer::new(vec![2, 1, 4, 2, 3]);
+        let result = realizer
+            .realize(&parse("5d{miss,blank,hit=1,hit=1}"))
+            .unwrap();
+        assert_eq!(2, result.sum());
+        let labels: Vec<_> = result
+            .labelled_results()
+            .map(|(_, _, label)| label)
+            .collect();
+        assert_eq!(vec!["blank", "miss", "hit", "blank", "hit"], labels);
+        let tally: Vec<_> = result.tally().into_iter().collect();
+        assert_eq!(vec![("blank", 2), ("hit", 2), ("miss", 1)], tally);
     }
 
     // I honestly don't know what the desired result for these two tests is.
@@ -516,14 +5238,14 @@ mod tests {
     fn realize_advantage_reroll_and_explode() {
         let mut realizer = MockRealizer::new(vec![1, 5, 3, 2]);
         let expression = parse("a2d6r!5");
-        assert_eq!(10, realizer.realize(&expression).sum());
+        assert_eq!(10, realizer.realize(&expression).unwrap().sum());
     }
 
     #[test]
     fn realize_disadvantage_reroll_and_explode() {
         let mut realizer = MockRealizer::new(vec![1, 5, 3, 2]);
         let expression = parse("s2d6re5");
-        assert_eq!(5, realizer.realize(&expression).sum());
+        assert_eq!(5, realizer.realize(&expression).unwrap().sum());
     }
 
     fn parse(s: &str) -> Expression {
@@ -551,6 +5273,132 @@ mod tests {
         assert_close(avg("2d6+3"), 10.0);
     }
 
+    #[test]
+    fn average_multi_term() {
+        assert_close(avg("2d6+1d4+3"), 12.5);
+    }
+
+    #[test]
+    fn average_4d6_keep_highest_3() {
+        assert_close(avg("4d6kh3"), 15869.0 / 1296.0);
+    }
+
+    #[test]
+    fn average_keep_matches_advantage() {
+        assert_close(avg("2d20kh1"), avg("ad20"));
+        assert_close(avg("2d20kl1"), avg("sd20"));
+        assert_close(avg("3d6dh0"), 10.5);
+        assert_close(avg("2d6r!5k2"), avg("2d6r!5"));
+        assert_close(avg("a2d6r!5k2"), avg("a2d6r!5"));
+    }
+
+    #[test]
+    fn average_pool() {
+        assert_close(avg("10d10>=8"), 3.0);
+        assert_close(avg("6d6>4f1"), 1.0);
+        assert_close(avg("6d6>4f1+2"), 3.0);
+    }
+
+    #[test]
+    fn average_pool_with_explosion() {
+        // Per die: P(success) / (1 - P(explode)) = 0.3 / 0.9
+        assert_close(avg("5d10>=8!"), 5.0 / 3.0);
+    }
+
+    #[test]
+    fn average_wod_pools() {
+        // Per die: P(success) / (1 - P(roll again))
+        assert_close(avg("7wod"), 7.0 * 0.3 / 0.9);
+        assert_close(avg("10wod8"), 10.0 * 0.3 / 0.7);
+        assert_close(avg("0wod"), 0.1 / 0.9);
+    }
+
+    #[test]
+    fn average_shadowrun_pools() {
+        assert_close(avg("12sr"), 4.0);
+        // Per die: P(hit) / (1 - P(six))
+        assert_close(avg("12sr!"), 12.0 * (2.0 / 6.0) / (5.0 / 6.0));
+    }
+
+    #[test]
+    fn blades_outcome_chances() {
+        let chances = |input: &str| parse(input).outcome_chances().unwrap();
+        let expected = [
+            (Outcome::Critical, 1.0 / 36.0),
+            (Outcome::FullSuccess, 10.0 / 36.0),
+            (Outcome::PartialSuccess, 16.0 / 36.0),
+            (Outcome::Bad, 9.0 / 36.0),
+        ];
+        let actual = chances("bitd2");
+        for ((outcome, chance), (expected, expected_chance)) in actual.into_iter().zip(expected) {
+            assert_eq!(outcome, expected);
+            assert_close(chance, expected_chance);
+        }
+
+        let zero: Vec<_> = chances("bitd0")
+            .into_iter()
+            .map(|(_, chance)| chance)
+            .collect();
+        assert_eq!(zero, vec![0.0, 1.0 / 36.0, 8.0 / 36.0, 27.0 / 36.0]);
+        for n in 1..=6 {
+            let total: f64 = chances(&format!("bitd{n}"))
+                .iter()
+                .map(|(_, chance)| chance)
+                .sum();
+            assert_close(total, 1.0);
+        }
+
+        assert_eq!(None, parse("bitd2+1").outcome_chances());
+        assert_eq!(None, parse("7wod").outcome_chances());
+    }
+
+    #[test]
+    fn average_savage_worlds_trait_rolls() {
+        assert_close(avg("sw8"), avg("max(1d8!,1d6!)"));
+        assert_close(avg("sw4+2"), avg("max(1d4!,1d6!)") + 2.0);
+    }
+
+    #[test]
+    fn average_arithmetic() {
+        assert_close(avg("(2d6+3)*2"), 20.0);
+        assert_close(avg("1d4*1d6"), 2.5 * 3.5);
+        assert_close(avg("1d20-1d4"), 8.0);
+        assert_close(avg("-(2d6)"), -7.0);
+    }
+
+    #[test]
+    fn average_rounded_division() {
+        // floor(k/2) for k in 1..=8 is 0,1,1,2,2,3,3,4
+        assert_close(avg("1d8/2"), 2.0);
+        assert_close(avg("1d8/^2"), 2.5);
+        assert_close(avg("(4d6kh3)/3"), avg_exact_floor_div("4d6kh3", 3));
+    }
+
+    fn avg_exact_floor_div(s: &str, divisor: i64) -> f64 {
+        let distribution = parse(s).root.distribution();
+        distribution
+            .iter()
+            .map(|(value, p)| value.div_euclid(divisor) as f64 * p)
+            .sum()
+    }
+
+    #[test]
+    fn average_fudge_and_percentile() {
+        assert_close(avg("4dF"), 0.0);
+        assert_close(avg("4dF+2"), 2.0);
+        assert_close(avg("d%"), 50.5);
+        // Advantage on a Fudge die: P(+) = 5/9, P(-) = 1/9
+        assert_close(avg("adF"), 4.0 / 9.0);
+        assert_close(avg("2dFkh1"), 4.0 / 9.0);
+    }
+
+    #[test]
+    fn average_custom_faces() {
+        assert_close(avg("d{1,1,2,2,3,4}"), 13.0 / 6.0);
+        assert_close(avg("2d{1,1,2,2,3,4}kh1"), avg("ad{1,1,2,2,3,4}"));
+        assert_close(avg("d{miss,hit=1,crit=2}+1"), 2.0);
+    }
+
     #[test]
     fn average_d20() {
         assert_close(avg("d20"), 10.5);
@@ -568,6 +5416,90 @@ mod tests {
         assert_close(avg("sd20"), 7.175);
     }
 
+    #[test]
+    fn average_best_and_worst_of_three() {
+        // E[max of three d20] = Σ k(k³ - (k-1)³) / 20³
+        let expected = (1..=20)
+            .map(|k: i32| k as f64 * (k.pow(3) - (k - 1).pow(3)) as f64)
+            .sum::<f64>()
+            / 8000.0;
+        assert_close(avg("aad20"), expected);
+        assert_close(avg("aad20"), avg("3d20kh1"));
+        assert_close(avg("ssd20"), avg("3d20kl1"));
+        assert_close(avg("aa2d6r!5"), avg("aa2d6r!5k2"));
+    }
+
+    #[test]
+    fn average_advantage_on_totals() {
+        assert_close(avg("a(d20)"), avg("ad20"));
+        assert_close(avg("ss(d20)"), avg("ssd20"));
+        assert_close(avg("aa(d20+2)"), avg("aad20") + 2.0);
+
+        // The higher of two totals of 2d6, over every way the four dice can land
+        let mut expected = 0.0;
+        for roll in 0..6_i32.pow(4) {
+            let die = |n: u32| (roll / 6_i32.pow(n) % 6 + 1) as f64;
+            expected += f64::max(die(0) + die(1), die(2) + die(3));
+        }
+        assert_close(avg("a(2d6)"), expected / 1296.0);
+    }
+
+    #[test]
+    fn average_with_clamps() {
+        // Reliable Talent: ten faces count as 10, then 11 through 20
+        assert_close(avg("d20min10"), (100.0 + 155.0) / 20.0);
+        assert_close(avg("2d6min2"), 2.0 * 22.0 / 6.0);
+        assert_close(avg("(1d6-3)min0"), 1.0);
+        // Totals of 8 through 12 lose 1 through 5: (1·5 + 2·4 + 3·3 + 4·2 + 5·1) / 36
+        assert_close(avg("(2d6)max7"), 7.0 - 35.0 / 36.0);
+
+        for text in ["d6!min2", "4d6min2kh3", "3d6r1max5", "d8!!min3"] {
+            let expression = parse(text);
+            assert_close(
+                expression.average_result(),
+                expression.root.distribution().mean(),
+            );
+        }
+    }
+
+    #[test]
+    fn average_subtracted_dice() {
+        assert_close(avg("1d20-1d4"), 8.0);
+        assert_close(avg("-3d6"), -10.5);
+        assert_close(avg("-(2d6-1d4)+10"), 5.5);
+
+        // Each of 24 pairs, counted by how far the d4 is above the d6
+        let expected: i32 = (1..=4)
+            .flat_map(|a| (1..=6).map(move |b| (a - b).max(0)))
+            .sum();
+        assert_close(avg("max(0, 1d4-1d6)"), expected as f64 / 24.0);
+    }
+
+    #[test]
+    fn average_functions() {
+        // Each of 48 pairs, counted by its higher die
+        let expected: i32 = (1..=6).flat_map(|a| (1..=8).map(move |b| a.max(b))).sum();
+        assert_close(avg("max(1d6, 1d8)"), expected as f64 / 48.0);
+
+        // |X - Y| for two d6 is 0 on six of 36 pairs, 1 on ten, 2 on eight, and so on
+        assert_close(avg("abs(1d6-1d6)"), 70.0 / 36.0);
+        assert_close(avg("min(d20, d20, d20)"), avg("3d20kl1"));
+        assert_close(avg("ceil(3d6/2)"), avg("3d6/^2"));
+        assert_close(avg("max(2d6, 7)"), 7.0 + 35.0 / 36.0);
+    }
+
+    #[test]
+    fn average_groups() {
+        assert_close(avg("{d20,d20}kh1"), 13.825);
+        assert_close(avg("{1d6,1d8}"), 8.0);
+        assert_close(avg("{4d6,4d6}dh1"), avg("min(4d6,4d6)"));
+
+        // Pooling the dice of 4d6 keeps them exactly as 4d6kh3 does.
+        assert_close(avg("{2d6+2d6}kh3"), avg("4d6kh3"));
+        let pooled = parse("{2d6+1d8}kh2");
+        assert_close(pooled.average_result(), pooled.root.distribution().mean());
+    }
+
     #[test]
     fn average_d6_explode_on_6() {
         // E = M(M+1)/(2(t-1)) = 42/10 = 4.2
@@ -580,6 +5512,92 @@ mod tests {
         assert_close(avg("d6!5"), 5.25);
     }
 
+    #[test]
+    fn average_d6_compound_matches_explode() {
+        assert_close(avg("d6!!"), 4.2);
+        assert_close(avg("a2d6r!!5"), avg("a2d6r!5"));
+    }
+
+    #[test]
+    fn average_d6_penetrate() {
+        // Each of the expected 6/5 rolls after the first loses one: 4.2 - 1/5
+        assert_close(avg("d6!p"), 4.0);
+        assert_close(avg("3d6!p5+1"), 3.0 * (5.25 - 0.5) + 1.0);
+    }
+
+    #[test]
+    fn average_penetrate_matches_distribution() {
+        for text in ["ad6r!p", "sd8!p7", "2dF!p", "3d6!p6>=5", "2d6!pkh1"] {
+            let expression = parse(text);
+            assert_close(
+                expression.average_result(),
+                expression.root.distribution().mean(),
+            );
+        }
+    }
+
+    #[test]
+    fn average_compound_pool() {
+        // A die succeeds only by rolling 6 and then at least 2: 1/6 * 5/6
+        assert_close(avg("3d6!!6>=8"), 3.0 * 5.0 / 36.0);
+    }
+
+    #[test]
+    fn average_reroll_once() {
+        // Great Weapon Fighting: (3+4+5+6)/6 + (2/6)(3.5) per die
+        assert_close(avg("2d6ro<3"), 2.0 * 25.0 / 6.0);
+        assert_close(avg("d6ro"), 23.5 / 6.0);
+
+        for text in [
+            "ad20ro",
+            "sd20ro",
+            "3d6ro<3!",
+            "2d{1,1,2,3}ro=1",
+            "4d6ro<2kh3",
+        ] {
+            let expression = parse(text);
+            assert_close(
+                expression.average_result(),
+                expression.root.distribution().mean(),
+            );
+        }
+    }
+
+    #[test]
+    fn average_conditions() {
+        assert_close(avg("d6r=1"), avg("d6r"));
+        assert_close(avg("d6r<3"), avg("d6r2"));
+        assert_close(avg("d6r>4"), 2.5);
+        // E = (M+1)M / (2(t-1)) = 110/16
+        assert_close(avg("d10!>=9"), 6.875);
+        assert_close(avg("d10!>8"), 6.875);
+        // Exploding on 1s: E = 20/6 + (1 + E)/6
+        assert_close(avg("d6!=1"), 4.2);
+    }
+
+    #[test]
+    fn average_with_explosion_limit() {
+        let mut profile = Profile::default();
+        profile.limits.max_explosions = 1;
+        let resolve = |text| parse(text).resolve(&profile).unwrap();
+
+        // Exploding at most once: 3.5 + 3.5/6
+        assert_close(resolve("d6!").average_result(), 3.5 * 7.0 / 6.0);
+        for text in ["ad6r!5", "3d10>=8!", "2d6!p", "sd8!!7", "3d6!kh2"] {
+            let expression = resolve(text);
+            assert_close(
+                expression.average_result(),
+                expression.root.distribution().mean(),
+            );
+        }
+
+        profile.limits.max_explosions = 0;
+        assert_close(
+            parse("ad6!").resolve(&profile).unwrap().average_result(),
+            avg("ad6"),
+        );
+    }
+
     #[test]
     fn average_2d6_reroll_1s() {
         // E per die = (r+1+M)/2 = 8/2 = 4; total = 2*4 = 8
@@ -592,14 +5610,34 @@ mod tests {
         assert_close(avg("d6r!5"), 40.0 / 6.0);
     }
 
+    fn reroll(max: i32) -> Reroll {
+        Reroll {
+            condition: Comparison::LessOrEqual(max),
+            once: false,
+        }
+    }
+
     fn count_max(count: i32, max: i32) -> Expression {
-        Expression {
+        single(dice(count, max))
+    }
+
+    fn dice(count: i32, max: i32) -> Dice {
+        Dice {
             count,
-            max,
+            die: Die::Standard(max),
             ..Default::default()
         }
     }
 
+    fn single(dice: Dice) -> Expression {
+        Expression {
+            root: Node::Dice(dice),
+            target: None,
+            repeat: None,
+            bands: None,
+        }
+    }
+
     struct MockRealizer<T> {
         source: T,
     }
@@ -1,20 +1,22 @@
 mod args;
 mod default_iter;
+mod distribution;
 mod error;
 mod expression;
 mod history;
+mod profile;
 mod realize;
 mod token;
 
-use std::{borrow::Cow, fs, io, iter, path::Path, slice};
+use std::{borrow::Cow, cmp, ops::Range, path::Path, slice};
 
-use args::{AddAlias, Args, Mode, PathConfig};
-use comfy_table::Table;
-use either::Either;
-use expression::{Expression, ExpressionParser};
-use fs::File;
-use hashbrown::{HashMap, HashSet};
+use args::{AddAlias, AddDie, Args, Mode, PathConfig, SetVariable};
+use comfy_table::{Row, Table};
+use error::ExpressionError;
+use expression::{Expression, ExpressionParser, Face, LegacyExpression, RealizedExpression};
+use hashbrown::HashSet;
 use history::History;
+use profile::Profile;
 use realize::{RandomRealizer, Realizer};
 use serde::{Deserialize, Serialize};
 use squirrel_rng::SquirrelRng;
@@ -40,11 +42,38 @@ impl<'a> IntoIterator for &'a Formula {
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "RawStoredExpression")]
 struct StoredExpression {
     text: String,
     expression: Expression,
 }
 
+/// A stored expression as written, which may predate the current shape of `Expression`.
+#[derive(Deserialize)]
+struct RawStoredExpression {
+    text: String,
+    expression: serde_json::Value,
+}
+
+impl TryFrom<RawStoredExpression> for StoredExpression {
+    type Error = serde_json::Error;
+
+    /// Reads the stored expression, converting it if it was stored in the single-term shape.
+    ///
+    /// The text isn't parsed again, as today's parser may read it differently from the one that
+    /// stored it: the old parser ignored all but the first modifier in 2d6+3+4.
+    fn try_from(raw: RawStoredExpression) -> Result<Self, Self::Error> {
+        let expression = match Expression::deserialize(&raw.expression) {
+            Ok(expression) => expression,
+            Err(e) => match LegacyExpression::deserialize(&raw.expression) {
+                Ok(legacy) => legacy.try_into().map_err(serde::de::Error::custom)?,
+                Err(_) => return Err(e),
+            },
+        };
+        Ok(StoredExpression::new(raw.text, expression))
+    }
+}
+
 impl StoredExpression {
     fn new(text: impl Into<String>, expression: Expression) -> Self {
         Self {
@@ -59,63 +88,84 @@ fn main() {
 
     if let Err(e) = run(&args) {
         eprintln!("{e}");
+        if let Some((text, span)) = e.span() {
+            eprintln!("{}", underline(text, span));
+        }
         std::process::exit(1);
     }
 }
 
+/// Shows an expression with a caret under the span where it went wrong.
+fn underline(text: &str, span: Range<usize>) -> String {
+    let indent = text[..span.start].chars().count();
+    let width = cmp::max(text[span].chars().count(), 1);
+    format!("  {text}\n  {}{}", " ".repeat(indent), "^".repeat(width))
+}
+
 fn run(args: &Args) -> Result<()> {
     let paths = args.path_config()?;
 
     match args.mode() {
         Mode::Norm => execute_expressions(&paths, args),
-        Mode::Average => print_averages(&paths, args.candidates()),
+        Mode::Average => print_averages(&paths, args.candidates(), args.dc),
         Mode::Add(alias) => add_alias(alias, paths.config()),
         Mode::Rem(alias) => rem_alias(alias, paths.config()),
+        Mode::Die(die) => add_die(die, paths.config()),
+        Mode::Set(set) => set_variable(set, paths.config()),
+        Mode::Variables => list_variables(paths.config()),
         Mode::List => list(paths.config()),
     }
 }
 
-/// Expands "counted" expressions
+/// Finds the alias a candidate names, either on its own or repeated (3#attack), along with the
+/// number of times to roll it.
 ///
-/// An expression of the form 2d6*2 expands *two instances of* the expression 2d6. (Note that on
-/// Linux systems it is necessary to either quote '2d6*2' or use 2d6x2 instead.) This function
-/// transforms a counted expression into one or more expressions of the same value.
-fn expand_expressions<'a, I>(candidates: I) -> impl Iterator<Item = &'a str>
-where
-    I: IntoIterator<Item = &'a str>,
-{
-    candidates
-        .into_iter()
-        .default_if_empty("1d20")
-        .map(|candidate| match candidate.split_once(['*', 'x', 'X']) {
-            Some((expr, count)) => (count.parse().unwrap_or(1usize), expr),
-            None => (1, candidate),
-        })
-        .flat_map(|(count, expr)| iter::repeat_n(expr, count))
+/// Aliases are looked up before anything is parsed, so an alias can be any word at all, such as
+/// axe, without being read as an expression. Naming an alias that couldn't be read is an error.
+fn find_alias<'a>(
+    profile: &'a Profile,
+    candidate: &'a str,
+) -> Result<Option<(u32, &'a str, &'a Formula)>> {
+    let (count, alias) = match candidate.split_once('#') {
+        Some((count, alias)) if !profile.aliases.contains_key(candidate) => {
+            match count.trim().parse().ok().filter(|&count| count > 0) {
+                Some(count) => (count, alias.trim()),
+                None => return Ok(None),
+            }
+        }
+        _ => (1, candidate),
+    };
+
+    if let Some(unreadable) = profile.unreadable.get(alias) {
+        return Err(error::Error::Alias(alias.into(), unreadable.reason.clone()));
+    }
+    Ok(profile
+        .aliases
+        .get(alias)
+        .map(|formula| (count, alias, formula)))
 }
 
-fn print_averages<'a, I>(path: &PathConfig, candidates: I) -> Result<()>
+fn print_averages<'a, I>(path: &PathConfig, candidates: I, dc: Option<i32>) -> Result<()>
 where
     I: IntoIterator<Item = &'a str>,
 {
     let parser = ExpressionParser::new();
-    let aliases = read_config(path.config())?;
+    let profile = Profile::read(path.config())?;
     let mut unique_filter = HashSet::new();
     let mut table = configure_table();
 
-    for expression in expand_expressions(candidates) {
-        if let Some(formula) = aliases.get(expression) {
+    for expression in candidates.into_iter().default_if_empty("1d20") {
+        if let Some((_, _, formula)) = find_alias(&profile, expression)? {
             for expression in formula.expressions.iter() {
                 if !unique_filter.contains(&expression.text) {
-                    let average = expression.expression.average_result();
-                    table.add_row(&[Cow::from(&expression.text), format!("{average:.02}").into()]);
+                    let compiled = expression.expression.resolve(&profile)?.or_target(dc);
+                    table.add_row(average_row(&expression.text, &compiled));
                     unique_filter.insert(expression.text.clone());
                 }
             }
         } else if !unique_filter.contains(expression) {
-            let compiled = parser.parse(expression)?;
-            let average = compiled.average_result();
-            table.add_row(&[Cow::from(expression), format!("{average:.02}").into()]);
+            let compiled = parser.parse(expression)?.resolve(&profile)?.or_target(dc);
+            table.add_row(average_row(expression, &compiled));
         }
     }
 
@@ -124,35 +174,99 @@ where
     Ok(())
 }
 
+/// The average of an expression, with the chance of reaching its target or landing in each of
+/// its bands.
+///
+/// A roll judged only by its outcome, e.g. bitd3, shows the chance of each outcome instead.
+fn average_row(text: &str, expression: &Expression) -> Row {
+    if let Some(chances) = expression.outcome_chances() {
+        let mut row = Row::from([text]);
+        for (outcome, chance) in chances {
+            row.add_cell(format!("{:.02}% {outcome}", chance * 100.0).into());
+        }
+        return row;
+    }
+
+    let average = expression.average_result();
+    let mut row = Row::from([Cow::from(text), format!("{average:.02}").into()]);
+    if let (Some(target), Some(chance)) = (expression.target(), expression.success_chance()) {
+        row.add_cell(format!("{:.02}% to reach {target}", chance * 100.0).into());
+    }
+    for (band, chance) in expression.band_chances().into_iter().flatten() {
+        row.add_cell(format!("{:.02}% {band}", chance * 100.0).into());
+    }
+    row
+}
+
+/// Adds a pass/fail column to a row for a roll with a target.
+fn with_check(mut row: Row, result: &RealizedExpression) -> Row {
+    if let Some(check) = result.check() {
+        row.add_cell(check.highlight().paint(check).into());
+    }
+    row
+}
+
 fn execute_expressions(paths: &PathConfig, args: &Args) -> Result<()> {
     let parser = ExpressionParser::new();
-    let aliases = read_config(paths.config())?;
+    let profile = Profile::read(paths.config())?;
 
     let mut realizer: RandomRealizer<SquirrelRng> = RandomRealizer::new();
     let mut realizer = realizer.with_logging();
     let mut history = History::new(paths.history());
     let mut table = configure_table();
-
-    let expressions = expand_expressions(args.candidates());
-    for expression in expressions {
-        if let Some(formula) = aliases.get(expression) {
-            table.add_row([expression, formula.comment.as_deref().unwrap_or("")]);
-
-            for expression in &formula.expressions {
-                let result = realizer.realize(&expression.expression);
-                table.add_row(&[
-                    Cow::from(result.sum().to_string()),
-                    Cow::from(&expression.text),
-                ]);
+    let band_column = has_bands(&profile, args.candidates().default_if_empty("1d20"));
+
+    for expression in args.candidates().default_if_empty("1d20") {
+        if let Some((count, alias, formula)) = find_alias(&profile, expression)? {
+            for number in 1..=count {
+                let header = match count {
+                    1 => Cow::from(alias),
+                    _ => Cow::from(format!("{alias} #{number}")),
+                };
+                table.add_row([header, Cow::from(formula.comment.as_deref().unwrap_or(""))]);
+
+                for expression in &formula.expressions {
+                    let compiled = expression.expression.resolve(&profile)?.or_target(args.dc);
+                    let repetitions = compiled.repetitions();
+
+                    // An expression stored with a count, like 6#4d6kh3, is numbered each time.
+                    for repetition in 1..=repetitions {
+                        let mut result = realizer.realize(&compiled)?;
+                        result.apply_resistances(&args.resist, &args.vulnerable)?;
+                        let text = match repetitions {
+                            1 => Cow::from(&expression.text),
+                            _ => Cow::from(format!("{} #{repetition}", expression.text)),
+                        };
+                        let mut row = result.sum_row(band_column);
+                        row.add_cell(format!("{text}{}", result.notes()).into());
+                        table.add_row(with_check(row, &result));
+                    }
+                }
             }
         } else {
-            let compiled = parser.parse(expression)?;
-            let result = realizer.realize(&compiled);
-
-            if args.verbose {
-                table.add_row([Either::Left(result.sum()), Either::Right(expression)]);
-            } else {
-                table.add_row(result);
+            let compiled = parser
+                .parse(expression)?
+                .resolve(&profile)?
+                .or_target(args.dc);
+            let count = compiled.repetitions();
+
+            for number in 1..=count {
+                let mut result = realizer.realize(&compiled)?;
+                result.apply_resistances(&args.resist, &args.vulnerable)?;
+
+                // Each roll of a repeated expression is numbered.
+                let number = (count > 1).then_some(number);
+                if args.verbose {
+                    let text = match number {
+                        Some(number) => Cow::from(format!("{expression} #{number}")),
+                        None => Cow::from(expression),
+                    };
+                    let mut row = result.sum_row(band_column);
+                    row.add_cell(format!("{text}{}", result.notes()).into());
+                    table.add_row(with_check(row, &result));
+                } else {
+                    table.add_row(result.into_row(number, band_column));
+                }
             }
         }
     }
@@ -166,9 +280,30 @@ fn execute_expressions(paths: &PathConfig, args: &Args) -> Result<()> {
     Ok(history.write()?)
 }
 
+/// Whether any of the expressions to roll has bands, giving the table a band column.
+///
+/// Expressions that can't be read are left for the roll itself to report.
+fn has_bands<'a>(profile: &Profile, candidates: impl IntoIterator<Item = &'a str>) -> bool {
+    let parser = ExpressionParser::new();
+    candidates
+        .into_iter()
+        .any(|candidate| match find_alias(profile, candidate) {
+            Ok(Some((_, _, formula))) => formula
+                .expressions
+                .iter()
+                .any(|stored| stored.expression.has_bands()),
+            Ok(None) => parser
+                .parse(candidate)
+                .is_ok_and(|expression| expression.has_bands()),
+            Err(_) => false,
+        })
+}
+
 fn configure_table() -> Table {
     let mut table = Table::new();
     table.load_preset(comfy_table::presets::NOTHING);
+    // Styled cells are coloured even when piped, like the values painted in other cells.
+    table.enforce_styling();
     table
 }
 
@@ -184,28 +319,68 @@ fn add_alias(add: &AddAlias, config: &Path) -> Result<()> {
         })
         .collect();
 
-    let mut aliases = read_config(config)?;
-    aliases.insert(
+    let mut profile = Profile::read(config)?;
+    profile.unreadable.remove(&add.alias);
+    profile.aliases.insert(
         add.alias.clone(),
         Formula {
             comment: add.comment.clone(),
             expressions: expressions?,
         },
     );
-    write_config(config, &aliases)?;
+    profile.write(config)?;
     Ok(())
 }
 
 fn rem_alias(alias: &str, config: &Path) -> Result<()> {
-    let mut aliases = read_config(config)?;
-    aliases.remove(alias);
-    write_config(config, &aliases)?;
+    let mut profile = Profile::read(config)?;
+    profile.aliases.remove(alias);
+    profile.unreadable.remove(alias);
+    profile.write(config)?;
+    Ok(())
+}
+
+fn add_die(add: &AddDie, config: &Path) -> Result<()> {
+    let faces = Face::parse_table(&add.faces.join(","))?;
+    let mut profile = Profile::read(config)?;
+    profile.dice.insert(add.name.clone(), faces);
+    profile.write(config)?;
+    Ok(())
+}
+
+fn set_variable(set: &SetVariable, config: &Path) -> Result<()> {
+    if !expression::is_identifier(&set.name) {
+        return Err(ExpressionError::Unsupported(
+            format!("@{}", set.name),
+            "a variable name is a letter followed by letters, digits or underscores",
+        )
+        .into());
+    }
+
+    let mut profile = Profile::read(config)?;
+    profile.variables.insert(set.name.clone(), set.value);
+    profile.write(config)?;
+    Ok(())
+}
+
+fn list_variables(config: &Path) -> Result<()> {
+    let profile = Profile::read(config)?;
+    let mut variables: Vec<_> = profile.variables.iter().collect();
+    variables.sort();
+    for (name, value) in variables {
+        println!("@{name} = {value}");
+    }
     Ok(())
 }
 
 fn list(config: &Path) -> Result<()> {
-    let aliases = read_config(config)?;
-    for (alias, formula) in aliases {
+    let profile = Profile::read(config)?;
+    for (name, faces) in &profile.dice {
+        let faces: Vec<_> = faces.iter().map(Face::to_string).collect();
+        println!("# d{{{}}}", name);
+        println!("  {}", faces.join(","));
+    }
+    for (alias, formula) in profile.aliases {
         println!("# {}", alias);
         if let Some(comment) = &formula.comment {
             println!("# {}", comment);
@@ -214,19 +389,8 @@ fn list(config: &Path) -> Result<()> {
             println!("  {}", expression.text);
         }
     }
-    Ok(())
-}
-
-fn read_config(path: &Path) -> io::Result<HashMap<String, Formula>> {
-    if !path.exists() {
-        return Ok(Default::default());
+    for (alias, unreadable) in &profile.unreadable {
+        println!("# {} (unreadable: {})", alias, unreadable.reason);
     }
-
-    let map = serde_json::from_reader(File::open(path)?)?;
-    Ok(map)
-}
-
-fn write_config(path: &Path, aliases: &HashMap<String, Formula>) -> io::Result<()> {
-    serde_json::to_writer_pretty(File::create(path)?, aliases)?;
     Ok(())
 }
@@ -0,0 +1,202 @@
+use std::{fs::File, io, path::Path};
+
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    Formula,
+    expression::{Face, Limits},
+};
+
+/// Everything stored in a profile's config file.
+///
+/// Aliases are first read as plain JSON (`A`), so that one that can't be read doesn't keep the
+/// rest of the profile from loading.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile<A = Formula> {
+    #[serde(default)]
+    pub aliases: HashMap<String, A>,
+    /// Named face tables, e.g. a combat die with hit, miss and crit faces
+    #[serde(default)]
+    pub dice: HashMap<String, Vec<Face>>,
+    /// Named values referenced in expressions, e.g. str in d20+@str
+    #[serde(default)]
+    pub variables: HashMap<String, i32>,
+    /// Caps on the number of dice and explosions in a single roll
+    #[serde(default)]
+    pub limits: Limits,
+    /// Aliases that couldn't be read, kept as stored so that writing the profile keeps them
+    #[serde(skip)]
+    pub unreadable: HashMap<String, UnreadableAlias>,
+}
+
+impl<A> Default for Profile<A> {
+    fn default() -> Self {
+        Profile {
+            aliases: HashMap::new(),
+            dice: HashMap::new(),
+            variables: HashMap::new(),
+            limits: Limits::default(),
+            unreadable: HashMap::new(),
+        }
+    }
+}
+
+/// An alias stored in a shape that can't be read, e.g. with an expression that no longer parses.
+#[derive(Clone, Debug)]
+pub struct UnreadableAlias {
+    stored: Value,
+    pub reason: String,
+}
+
+/// Config files written before profiles held anything but aliases are a bare alias map.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredProfile {
+    Profile(Profile<Value>),
+    Aliases(HashMap<String, Value>),
+}
+
+impl Profile {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+
+        let stored = match serde_json::from_reader(File::open(path)?)? {
+            StoredProfile::Profile(profile) => profile,
+            StoredProfile::Aliases(aliases) => Profile {
+                aliases,
+                ..Default::default()
+            },
+        };
+
+        let mut profile = Profile {
+            dice: stored.dice,
+            variables: stored.variables,
+            limits: stored.limits,
+            ..Default::default()
+        };
+        for (name, stored) in stored.aliases {
+            match Formula::deserialize(&stored) {
+                Ok(formula) => {
+                    profile.aliases.insert(name, formula);
+                }
+                Err(e) => {
+                    let reason = e.to_string();
+                    profile
+                        .unreadable
+                        .insert(name, UnreadableAlias { stored, reason });
+                }
+            }
+        }
+        Ok(profile)
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut stored = serde_json::to_value(self)?;
+        if let Some(aliases) = stored.get_mut("aliases").and_then(Value::as_object_mut) {
+            for (name, alias) in &self.unreadable {
+                aliases.entry(name).or_insert_with(|| alias.stored.clone());
+            }
+        }
+        serde_json::to_writer_pretty(File::create(path)?, &stored)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use serde::Deserialize;
+
+    use super::{Profile, StoredProfile};
+    use crate::{Formula, expression::ExpressionParser};
+
+    #[test]
+    fn reads_bare_alias_map() {
+        let text = r#"{ "init": { "comment": null, "expressions": [] } }"#;
+        let profile = serde_json::from_str(text).unwrap();
+        assert!(matches!(profile, StoredProfile::Aliases(aliases) if aliases.contains_key("init")));
+    }
+
+    #[test]
+    fn reads_baseline_aliases() {
+        // Aliases stored before expressions had more than one term are converted from their
+        // fields, not parsed again, so they roll as they always did.
+        let text = r#"{
+            "axe": { "comment": "great axe", "expressions": [{
+                "text": "1d12+3",
+                "expression": { "count": 1, "max": 12, "modifier": 3, "advantage": "Normal",
+                    "reroll": null, "explode": null }
+            }] },
+            "check": { "comment": null, "expressions": [{
+                "text": "20+5",
+                "expression": { "count": 1, "max": 20, "modifier": 5, "advantage": "Advantage",
+                    "reroll": 1, "explode": null }
+            }] },
+            "damage": { "comment": null, "expressions": [{
+                "text": "2d6+3+4",
+                "expression": { "count": 2, "max": 6, "modifier": 3, "advantage": "Normal",
+                    "reroll": null, "explode": 6 }
+            }] }
+        }"#;
+        let Ok(StoredProfile::Aliases(aliases)) = serde_json::from_str(text) else {
+            panic!("expected a bare alias map");
+        };
+        let expression = |alias: &str| {
+            let formula = Formula::deserialize(&aliases[alias]).unwrap();
+            formula.expressions[0].expression.clone()
+        };
+        let parse = |text| ExpressionParser::new().parse(text).unwrap();
+        assert_eq!(expression("axe"), parse("1d12+3"));
+        assert_eq!(expression("check"), parse("a1d20r<=1+5"));
+        assert_eq!(expression("damage"), parse("2d6!>=6+3"));
+
+        // A legacy alias that could never stop rerolling can't be read.
+        let endless = r#"{ "comment": null, "expressions": [{ "text": "1d6r6",
+            "expression": { "count": 1, "max": 6, "modifier": 0, "advantage": "Normal",
+                "reroll": 6, "explode": null } }] }"#;
+        assert!(serde_json::from_str::<Formula>(endless).is_err());
+    }
+
+    #[test]
+    fn keeps_unreadable_aliases() {
+        let path = env::temp_dir().join(format!("roll-profile-{}", process::id()));
+        let text = r#"{
+            "aliases": {
+                "axe": { "comment": null, "expressions": [{ "text": "1d12+3", "expression": {
+                    "count": 1, "max": 12, "modifier": 3, "advantage": "Normal",
+                    "reroll": null, "explode": null } }] },
+                "bad": { "comment": null, "expressions": [{ "text": "1d12+", "expression": 3 }] }
+            },
+            "variables": { "str": 4 }
+        }"#;
+        fs::write(&path, text).unwrap();
+
+        // The alias that can't be read is set aside, along with the reason.
+        let profile = Profile::read(&path).unwrap();
+        assert!(profile.aliases.contains_key("axe"));
+        assert_eq!(Some(&4), profile.variables.get("str"));
+        assert!(profile.unreadable["bad"].reason.contains("expected"));
+
+        // It's written back as it was, so it isn't lost.
+        profile.write(&path).unwrap();
+        let profile = Profile::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(profile.aliases.contains_key("axe"));
+        assert!(profile.unreadable.contains_key("bad"));
+    }
+
+    #[test]
+    fn reads_profile() {
+        let text = r#"{ "aliases": {}, "dice": { "coin": [{ "value": 1, "label": "heads" }] } }"#;
+        let profile = serde_json::from_str(text).unwrap();
+        assert!(
+            matches!(profile, StoredProfile::Profile(profile) if profile.dice.contains_key("coin"))
+        );
+    }
+}
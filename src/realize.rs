@@ -1,7 +1,7 @@
 use hashbrown::HashMap;
 use rand::{
-    distr::{Distribution, Uniform},
     Rng,
+    distr::{Distribution, Uniform},
 };
 use smallvec::SmallVec;
 
@@ -1,61 +1,113 @@
-use regex::Regex;
+use std::ops::Range;
 
-pub trait TokenExtractor {
-    /// returns a boolean signifying whether or not a token was found along with an optional
-    /// associated value.
-    fn extract<'a>(&self, text: &'a str) -> (bool, Option<&'a str>);
-}
+/// Operators and punctuation, longest first so that >= is not read as >.
+static SYMBOLS: &[&str] = &[
+    ">=", "<=", "!!", "!p", "/^", "!", "#", "%", "(", ")", "*", "+", ",", "-", "/", "<", "=", ">",
+    "[", "]", "{", "}",
+];
+
+/// Letters with a meaning of their own, longest first so that ro is not read as r.
+static WORDS: &[&str] = &[
+    "floor", "bands", "ceil", "bitd", "abs", "max", "min", "wod", "sr", "sw", "ro", "kh", "kl",
+    "dh", "dl", "vs", "cf", "a", "A", "c", "d", "D", "e", "f", "F", "k", "r", "s", "S",
+];
 
-pub struct ExplodeTokenExtractor {
-    expr: Regex,
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Token<'a> {
+    Number(&'a str),
+    /// A modifier or keyword, e.g. d, kh or ro
+    Word(&'a str),
+    /// An operator or punctuation, e.g. +, >= or !
+    Symbol(&'a str),
+    /// A profile variable, e.g. @str (stored without the @)
+    Variable(&'a str),
+    /// A character that means nothing in an expression (though it may appear in a face table)
+    Unknown(&'a str),
 }
 
-impl ExplodeTokenExtractor {
-    fn new() -> Self {
-        Self {
-            expr: Regex::new(r#"(!|e)(\d+)?"#).unwrap(),
+/// Splits an expression into tokens and their byte spans, skipping whitespace.
+pub fn tokenize(text: &str) -> Vec<(Token<'_>, Range<usize>)> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+
+    while let Some(c) = text[position..].chars().next() {
+        let rest = &text[position..];
+        if c.is_whitespace() {
+            position += c.len_utf8();
+            continue;
         }
-    }
-}
 
-impl Default for ExplodeTokenExtractor {
-    fn default() -> Self {
-        Self::new()
+        let token = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            Token::Number(&rest[..len])
+        } else if c == '@' && name_len(&rest[1..]) > 0 {
+            Token::Variable(&rest[1..=name_len(&rest[1..])])
+        } else if let Some(symbol) = SYMBOLS.iter().find(|&&symbol| rest.starts_with(symbol)) {
+            Token::Symbol(symbol)
+        } else if let Some(word) = WORDS.iter().find(|&&word| rest.starts_with(word)) {
+            Token::Word(word)
+        } else {
+            Token::Unknown(&rest[..c.len_utf8()])
+        };
+
+        let len = match token {
+            Token::Number(text)
+            | Token::Word(text)
+            | Token::Symbol(text)
+            | Token::Unknown(text) => text.len(),
+            Token::Variable(name) => name.len() + 1,
+        };
+        tokens.push((token, position..position + len));
+        position += len;
     }
+
+    tokens
 }
 
-impl TokenExtractor for ExplodeTokenExtractor {
-    fn extract<'a>(&self, text: &'a str) -> (bool, Option<&'a str>) {
-        self.expr
-            .captures(text)
-            .map(|cx| (true, cx.get(2).map(|cx| cx.as_str())))
-            .unwrap_or((false, None))
+/// Length of the name at the start of `text`, e.g. the str in @str+2. A name starts with a
+/// letter.
+fn name_len(text: &str) -> usize {
+    if !text.starts_with(char::is_alphabetic) {
+        return 0;
     }
+    text.find(|c: char| !c.is_alphanumeric() && c != '_')
+        .unwrap_or(text.len())
 }
 
-pub struct RerollTokenExtractor {
-    expr: Regex,
-}
+#[cfg(test)]
+mod tests {
+    use super::{Token, tokenize};
 
-impl RerollTokenExtractor {
-    fn new() -> Self {
-        Self {
-            expr: Regex::new(r#"r(\d+)?"#).unwrap(),
-        }
+    #[test]
+    fn tokenize_dice() {
+        let tokens: Vec<_> = tokenize("a2d6ro<3!!").into_iter().map(|(t, _)| t).collect();
+        assert_eq!(
+            vec![
+                Token::Word("a"),
+                Token::Number("2"),
+                Token::Word("d"),
+                Token::Number("6"),
+                Token::Word("ro"),
+                Token::Symbol("<"),
+                Token::Number("3"),
+                Token::Symbol("!!"),
+            ],
+            tokens
+        );
     }
-}
 
-impl Default for RerollTokenExtractor {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn tokenize_variables() {
+        let tokens: Vec<_> = tokenize("d20+@str_mod+@1").into_iter().collect();
+        assert_eq!((Token::Variable("str_mod"), 4..12), tokens[3]);
+        assert_eq!((Token::Unknown("@"), 13..14), tokens[5]);
     }
-}
 
-impl TokenExtractor for RerollTokenExtractor {
-    fn extract<'a>(&self, text: &'a str) -> (bool, Option<&'a str>) {
-        self.expr
-            .captures(text)
-            .map(|cx| (true, cx.get(1).map(|cx| cx.as_str())))
-            .unwrap_or((false, None))
+    #[test]
+    fn tokenize_spans_skip_whitespace() {
+        let spans: Vec<_> = tokenize("2d6 + x").into_iter().map(|(_, s)| s).collect();
+        assert_eq!(vec![0..1, 1..2, 2..3, 4..5, 6..7], spans);
     }
 }